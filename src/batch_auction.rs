@@ -0,0 +1,142 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::TreeMap;
+use near_sdk::json_types::U128;
+use near_sdk::log;
+use near_sdk::serde::{Deserialize, Serialize};
+
+use crate::*;
+use crate::sale::*;
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BatchAuctionConfig {
+    /// Amount of distribute tokens for sale.
+    pub supply_amount: U128,
+}
+
+/// Bids of the batch auction and state of the clearing pass.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct BatchAuction {
+    /// Distribute tokens demanded at each limit price.
+    pub demand: TreeMap<Balance, Balance>,
+    /// Lowest price level processed by the clearing pass so far.
+    pub cursor: Option<Balance>,
+    /// Demand of the processed price levels above the clearing price.
+    pub filled_demand: Balance,
+    /// Demand at the clearing price. Zero if all the bids are filled in full.
+    pub marginal_demand: Balance,
+    /// Part of the demand at the clearing price that is filled.
+    pub marginal_filled: Balance,
+}
+
+impl BatchAuction {
+    pub fn new(sale_id: u64) -> Self {
+        Self {
+            demand: TreeMap::new(StorageKey::BatchAuctionDemand { sale_id }),
+            cursor: None,
+            filled_demand: 0,
+            marginal_demand: 0,
+            marginal_filled: 0,
+        }
+    }
+}
+
+impl Sale {
+    fn batch_auction(&mut self) -> &mut BatchAuction {
+        self.batch_auction.as_mut().expect("ERR_NO_BATCH_AUCTION")
+    }
+
+    /// Amount of distribute tokens the deposit bids for at the given limit price.
+    fn get_batch_auction_bid_amount(&self, deposit_amount: Balance, limit_price: Balance) -> Balance {
+        let decimals = self.distribute_token_decimals.expect("ERR_NO_TOKEN_DECIMALS");
+        (
+            U256::from(u128::pow(10, decimals as u32))
+                * U256::from(deposit_amount)
+                / U256::from(limit_price)
+        ).as_u128()
+    }
+
+    /// Adds the deposit to the bid of the account. Must be called before account amount is updated.
+    pub(crate) fn internal_batch_auction_bid(&mut self, account_sale: &mut SaleAccount, deposit_amount: Balance, limit_price: Balance) {
+        assert!(limit_price >= self.price, "ERR_LIMIT_PRICE_TOO_LOW");
+        if let Some(account_limit_price) = account_sale.limit_price {
+            assert_eq!(account_limit_price.0, limit_price, "ERR_WRONG_LIMIT_PRICE");
+        }
+        account_sale.limit_price = Some(U128(limit_price));
+
+        let old_bid = self.get_batch_auction_bid_amount(account_sale.amount.0, limit_price);
+        let new_bid = self.get_batch_auction_bid_amount(account_sale.amount.0 + deposit_amount, limit_price);
+        let batch_auction = self.batch_auction();
+        let demand = batch_auction.demand.get(&limit_price).unwrap_or(0);
+        batch_auction.demand.insert(&limit_price, &(demand - old_bid + new_bid));
+    }
+
+    /// Distribute tokens filled for the account and refund of the deposit after the clearing price is found.
+    pub(crate) fn get_batch_auction_fill(&self, account_sale: &SaleAccount) -> (Balance, Balance) {
+        let clearing_price = self.clearing_price.expect("ERR_AUCTION_NOT_SETTLED");
+        let batch_auction = self.batch_auction.as_ref().expect("ERR_NO_BATCH_AUCTION");
+        let limit_price = account_sale.limit_price.expect("ERR_NO_LIMIT_PRICE").0;
+        let bid_amount = self.get_batch_auction_bid_amount(account_sale.amount.0, limit_price);
+
+        let tokens = if limit_price > clearing_price || (limit_price == clearing_price && batch_auction.marginal_demand == 0) {
+            bid_amount
+        } else if limit_price == clearing_price {
+            (
+                U256::from(bid_amount)
+                    * U256::from(batch_auction.marginal_filled)
+                    / U256::from(batch_auction.marginal_demand)
+            ).as_u128()
+        } else {
+            0
+        };
+        let purchase_value = get_purchase_value(self, tokens, clearing_price);
+        (tokens, account_sale.amount.0 - purchase_value)
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Finds the clearing price of the batch auction after it ended.
+    /// Goes over at most `limit` price levels, call again until it returns the clearing price.
+    pub fn settle_batch_auction(&mut self, sale_id: u64, limit: u64) -> Option<U128> {
+        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        assert!(sale.sale_type == SaleType::BatchAuction, "ERR_NOT_BATCH_AUCTION");
+        assert!(env::block_timestamp() > sale.end_date, "ERR_SALE_IN_PROGRESS");
+        assert!(sale.clearing_price.is_none(), "ERR_ALREADY_SETTLED");
+
+        let supply_amount = sale.distribute_supply_amount.expect("ERR_MUST_HAVE_SUPPLY_AMOUNT");
+        let reserve_price = sale.price;
+        let batch_auction = sale.batch_auction();
+        let mut clearing_price = None;
+        for _ in 0..limit {
+            let level = match batch_auction.cursor {
+                Some(cursor) => batch_auction.demand.lower(&cursor),
+                None => batch_auction.demand.max(),
+            };
+            if let Some(price) = level {
+                let demand = batch_auction.demand.get(&price).unwrap();
+                if batch_auction.filled_demand + demand >= supply_amount {
+                    batch_auction.marginal_demand = demand;
+                    batch_auction.marginal_filled = supply_amount - batch_auction.filled_demand;
+                    clearing_price = Some(price);
+                    break;
+                }
+                batch_auction.filled_demand += demand;
+                batch_auction.cursor = Some(price);
+            } else {
+                // Undersubscribed: every bid is filled in full at the reserve price.
+                clearing_price = Some(reserve_price);
+                break;
+            }
+        }
+
+        if let Some(clearing_price) = clearing_price {
+            let sold_amount = batch_auction.filled_demand + batch_auction.marginal_filled;
+            sale.sold_amount = sold_amount;
+            sale.clearing_price = Some(clearing_price);
+            log!("Sale #{} settled at {}. Sold: {}", sale_id, clearing_price, sale.sold_amount);
+        }
+        self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+        clearing_price.map(U128)
+    }
+}
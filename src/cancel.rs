@@ -0,0 +1,61 @@
+use near_sdk::log;
+
+use crate::*;
+use crate::events::emit_sale_update;
+use crate::sale::*;
+use crate::soft_cap::internal_record_full_refund;
+
+#[near_bindgen]
+impl Contract {
+    /// Cancels the sale with deposits. Participants get back all of their deposits.
+    pub fn cancel_sale(&mut self, sale_id: u64) {
+        assert_eq!(
+            self.owner_id,
+            env::predecessor_account_id(),
+            "ERR_MUST_BE_OWNER"
+        );
+        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        assert!(!sale.cancelled, "ERR_ALREADY_CANCELLED");
+        sale.assert_not_finalized();
+        assert!(!sale.claim_available, "ERR_CLAIM_AVAILABLE");
+        // Withdrawn proceeds would not be enough to refund everyone.
+        assert_eq!(sale.withdrawn_amount, 0, "ERR_PROCEEDS_WITHDRAWN");
+        sale.cancelled = true;
+        log!("Sale #{} cancelled. Deposits to refund: {}", sale_id, sale.collected_amount);
+        self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+        emit_sale_update(sale_id, "cancelled");
+    }
+
+    /// Sends the deposits back to up to `limit` accounts of the cancelled or failed sale.
+    /// Returns number of refunds sent.
+    pub fn refund_sale_accounts(&mut self, sale_id: u64, from_index: u64, limit: u64) -> u64 {
+        assert_eq!(
+            self.owner_id,
+            env::predecessor_account_id(),
+            "ERR_MUST_BE_OWNER"
+        );
+        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        assert!(sale.is_full_refund(), "ERR_REFUND_NOT_AVAILABLE");
+
+        let keys = sale.account_sales.keys_as_vector();
+        let account_ids: Vec<AccountId> = (from_index..std::cmp::min(from_index + limit, keys.len()))
+            .map(|index| keys.get(index).unwrap())
+            .collect();
+        let mut refunds = vec![];
+        for account_id in account_ids {
+            let amount_to_refund = internal_record_full_refund(&mut sale, &account_id);
+            if amount_to_refund > 0 {
+                let (token_account_id, token_amount) = sale.internal_record_token_refund(&account_id, amount_to_refund);
+                refunds.push((account_id, amount_to_refund, token_account_id, token_amount));
+            }
+        }
+        self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+
+        let num_refunds = refunds.len() as u64;
+        for (account_id, amount_to_refund, token_account_id, token_amount) in refunds {
+            log!("Refund {} to {}", amount_to_refund, account_id);
+            self.refund_purchase(account_id, amount_to_refund, token_account_id, token_amount, sale_id);
+        }
+        num_refunds
+    }
+}
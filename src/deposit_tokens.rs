@@ -0,0 +1,265 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+
+use crate::*;
+use crate::events::emit_sale_update;
+use crate::native_near::is_native_near_token;
+use crate::sale::*;
+
+/// Rate 10^24 => one unit of the token is worth one unit of deposit_token_id.
+pub(crate) const RATE_DENOMINATOR: u128 = 1_000_000_000_000_000_000_000_000;
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DepositTokenRate {
+    pub token_id: AccountId,
+    /// Units of deposit_token_id for RATE_DENOMINATOR units of the token.
+    pub rate: U128,
+}
+
+/// Token accepted by the sale besides deposit_token_id, which stays the accounting unit of the sale.
+/// In the sales priced in USD, deposit_token_id is one of them and `rate` is the last oracle rate.
+/// Amounts are in the units of the token.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DepositToken {
+    pub token_id: AccountId,
+    pub rate: U128,
+    pub collected_amount: U128,
+    /// collected_amount in the units of deposit_token_id.
+    pub collected_units: U128,
+    pub refunded_amount: U128,
+    pub withdrawn_amount: U128,
+}
+
+/// Deposit of the account in a token other than deposit_token_id.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenDeposit {
+    pub token_id: AccountId,
+    pub amount: U128,
+    /// Each deposit with its oracle rate. Empty unless the sale is priced in USD.
+    pub usd_deposits: Vec<UsdDeposit>,
+}
+
+/// Deposit into the sale priced in USD, valued at the oracle rate of the deposit time.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct UsdDeposit {
+    /// Accepted part of the deposit, in the units of the token.
+    pub amount: U128,
+    /// USD units for RATE_DENOMINATOR units of the token.
+    pub rate: U128,
+    pub usd_amount: U128,
+    pub timestamp: U64,
+}
+
+/// Amount sent in the token the account paid with.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenAmount {
+    pub token_id: AccountId,
+    pub amount: U128,
+}
+
+impl DepositToken {
+    pub fn new(deposit_token_rate: DepositTokenRate) -> Self {
+        assert_ne!(deposit_token_rate.rate.0, 0, "ERR_WRONG_RATE");
+        Self {
+            token_id: deposit_token_rate.token_id,
+            rate: deposit_token_rate.rate,
+            collected_amount: U128(0),
+            collected_units: U128(0),
+            refunded_amount: U128(0),
+            withdrawn_amount: U128(0),
+        }
+    }
+}
+
+impl Sale {
+    /// Deposits in deposit_token_id are the accounting units of the sale, unless the sale is priced in USD.
+    pub(crate) fn is_unit_token(&self, token_id: &AccountId) -> bool {
+        self.usd_oracle.is_none() && &self.deposit_token_id == token_id
+    }
+
+    fn get_deposit_token(&mut self, token_id: &AccountId) -> Option<&mut DepositToken> {
+        self.deposit_tokens.iter_mut().find(|deposit_token| &deposit_token.token_id == token_id)
+    }
+
+    pub(crate) fn assert_deposit_token(&self, token_id: &AccountId) {
+        assert!(
+            &self.deposit_token_id == token_id
+                || self.deposit_tokens.iter().any(|deposit_token| &deposit_token.token_id == token_id),
+            "ERR_WRONG_TOKEN"
+        );
+    }
+
+    pub(crate) fn get_deposit_token_rate(&self, token_id: &AccountId) -> U128 {
+        self.deposit_tokens
+            .iter()
+            .find(|deposit_token| &deposit_token.token_id == token_id)
+            .map(|deposit_token| deposit_token.rate)
+            .unwrap_or(U128(RATE_DENOMINATOR))
+    }
+
+    pub(crate) fn internal_set_deposit_token_rate(&mut self, token_id: &AccountId, rate: Balance) {
+        self.get_deposit_token(token_id).expect("ERR_WRONG_TOKEN").rate = U128(rate);
+    }
+
+    /// Converts the amount of the deposit token into the sale units.
+    pub(crate) fn get_deposit_units(&self, token_id: &AccountId, amount: Balance) -> Balance {
+        if self.is_unit_token(token_id) {
+            return amount;
+        }
+        let deposit_token = self
+            .deposit_tokens
+            .iter()
+            .find(|deposit_token| &deposit_token.token_id == token_id)
+            .expect("ERR_WRONG_TOKEN");
+        (U256::from(amount) * U256::from(deposit_token.rate.0) / U256::from(RATE_DENOMINATOR)).as_u128()
+    }
+
+    /// Part of the deposit in the token units needed to cover `units`, rounded up but never above `amount`.
+    pub(crate) fn get_used_token_amount(&self, token_id: &AccountId, amount: Balance, units: Balance) -> Balance {
+        if self.is_unit_token(token_id) {
+            return units;
+        }
+        let rate = self.deposit_tokens.iter().find(|deposit_token| &deposit_token.token_id == token_id).unwrap().rate.0;
+        std::cmp::min(
+            amount,
+            ((U256::from(units) * U256::from(RATE_DENOMINATOR) + U256::from(rate) - 1) / U256::from(rate)).as_u128(),
+        )
+    }
+
+    /// Records the accepted deposit in the token of the account.
+    /// Account pays with a single token: all of its deposits into the sale must be in the token of the first one,
+    /// so that its refund can be converted back into that token.
+    pub(crate) fn internal_record_token_deposit(
+        &mut self,
+        account_sale: &mut SaleAccount,
+        token_id: &AccountId,
+        token_amount: Balance,
+        units: Balance,
+    ) {
+        if self.is_unit_token(token_id) {
+            assert!(account_sale.token_deposit.is_none(), "ERR_WRONG_TOKEN");
+            return;
+        }
+        if account_sale.token_deposit.is_none() {
+            // Account already paid in deposit_token_id.
+            assert_eq!(account_sale.amount.0, 0, "ERR_WRONG_TOKEN");
+        }
+        let token_deposit = account_sale.token_deposit.get_or_insert(TokenDeposit {
+            token_id: token_id.clone(),
+            amount: U128(0),
+            usd_deposits: vec![],
+        });
+        assert_eq!(&token_deposit.token_id, token_id, "ERR_WRONG_TOKEN");
+        token_deposit.amount = U128(token_deposit.amount.0 + token_amount);
+        let usd_oracle = self.usd_oracle.is_some();
+        let deposit_token = self.get_deposit_token(token_id).unwrap();
+        if usd_oracle {
+            token_deposit.usd_deposits.push(UsdDeposit {
+                amount: U128(token_amount),
+                rate: deposit_token.rate,
+                usd_amount: U128(units),
+                timestamp: U64(env::block_timestamp()),
+            });
+        }
+        deposit_token.collected_amount = U128(deposit_token.collected_amount.0 + token_amount);
+        deposit_token.collected_units = U128(deposit_token.collected_units.0 + units);
+    }
+
+    /// Converts the refund of the account into the token it paid with.
+    /// Returns the token and the amount to send.
+    pub(crate) fn internal_record_token_refund(&mut self, account_id: &AccountId, units: Balance) -> (AccountId, Balance) {
+        let account_sale: SaleAccount = self.account_sales.get(account_id).expect("ERR_NO_DATA").into();
+        match account_sale.token_deposit {
+            Some(token_deposit) => {
+                let token_amount = (
+                    U256::from(units) * U256::from(token_deposit.amount.0) / U256::from(account_sale.amount.0)
+                ).as_u128();
+                let deposit_token = self.get_deposit_token(&token_deposit.token_id).expect("ERR_WRONG_TOKEN");
+                deposit_token.refunded_amount = U128(deposit_token.refunded_amount.0 + token_amount);
+                (token_deposit.token_id, token_amount)
+            }
+            None => (self.deposit_token_id.clone(), units),
+        }
+    }
+
+    pub(crate) fn internal_rollback_token_refund(&mut self, token_id: &AccountId, token_amount: Balance) {
+        if let Some(deposit_token) = self.get_deposit_token(token_id) {
+            deposit_token.refunded_amount = U128(deposit_token.refunded_amount.0 - token_amount);
+        }
+    }
+
+    /// Proceeds of the token that can be withdrawn now.
+    /// Each token gets the same share of its deposits as the sale keeps of collected_amount.
+    pub(crate) fn get_available_token_proceeds(&self, token_id: &AccountId) -> Balance {
+        if self.is_unit_token(token_id) {
+            return self.get_available_proceeds();
+        }
+        let deposit_token = self
+            .deposit_tokens
+            .iter()
+            .find(|deposit_token| &deposit_token.token_id == token_id)
+            .expect("ERR_WRONG_TOKEN");
+        let collected_amount = self.get_collected_amount();
+        if collected_amount == 0 {
+            return 0;
+        }
+        let kept_amount = collected_amount.saturating_sub(self.get_reserved_amount());
+        (U256::from(deposit_token.collected_amount.0) * U256::from(kept_amount) / U256::from(collected_amount))
+            .as_u128()
+            .saturating_sub(deposit_token.withdrawn_amount.0)
+    }
+
+    pub(crate) fn internal_record_token_withdraw(&mut self, token_id: &AccountId, amount: Balance) {
+        if self.is_unit_token(token_id) {
+            self.withdrawn_amount += amount;
+        } else {
+            let deposit_token = self.get_deposit_token(token_id).unwrap();
+            deposit_token.withdrawn_amount = U128(deposit_token.withdrawn_amount.0 + amount);
+        }
+    }
+
+    pub(crate) fn internal_rollback_token_withdraw(&mut self, token_id: &AccountId, amount: Balance) {
+        if self.is_unit_token(token_id) {
+            self.withdrawn_amount -= amount;
+        } else {
+            let deposit_token = self.get_deposit_token(token_id).unwrap();
+            deposit_token.withdrawn_amount = U128(deposit_token.withdrawn_amount.0 - amount);
+        }
+    }
+
+    /// Part of collected_amount paid with deposit_token_id. Zero for the sales priced in USD.
+    pub(crate) fn get_primary_collected_amount(&self) -> Balance {
+        self.get_collected_amount()
+            - self.deposit_tokens.iter().map(|deposit_token| deposit_token.collected_units.0).sum::<Balance>()
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Adds the token to the accepted tokens of the sale or updates its rate.
+    /// New rate only applies to the future deposits.
+    #[private]
+    pub fn update_sale_deposit_token(&mut self, sale_id: u64, token_id: AccountId, rate: U128) {
+        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        assert_ne!(sale.deposit_token_id, token_id, "ERR_WRONG_TOKEN");
+        assert!(!is_native_near_token(&token_id), "ERR_WRONG_TOKEN");
+        assert_ne!(rate.0, 0, "ERR_WRONG_RATE");
+        match sale.get_deposit_token(&token_id) {
+            Some(deposit_token) => deposit_token.rate = rate,
+            None => sale.deposit_tokens.push(DepositToken::new(DepositTokenRate { token_id, rate })),
+        }
+        self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+        emit_sale_update(sale_id, "deposit_tokens");
+    }
+
+    pub fn get_sale_deposit_tokens(&self, sale_id: u64) -> Vec<DepositToken> {
+        let sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        sale.deposit_tokens
+    }
+}
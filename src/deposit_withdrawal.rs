@@ -0,0 +1,103 @@
+use near_sdk::json_types::U128;
+use near_sdk::log;
+
+use crate::*;
+use crate::events::DepositWithdrawData;
+use crate::sale::*;
+
+/// 1 => 0.01% of the withdrawn amount (penalty / 10000).
+pub(crate) const WITHDRAWAL_PENALTY_DENOMINATOR: u128 = 10000;
+
+impl Contract {
+    /// Lowers the account deposit and affiliate rewards of its referrers.
+    fn internal_reduce_deposit(&mut self, sale: &mut Sale, account_id: &AccountId, amount: Balance) {
+        let mut account_sale: SaleAccount = sale.account_sales.get(account_id).expect("ERR_NO_DATA").into();
+        assert!(account_sale.token_deposit.is_none(), "ERR_WITHDRAW_NOT_ALLOWED");
+        assert!(amount <= account_sale.amount.0, "ERR_NOT_ENOUGH_DEPOSIT");
+        let old_amount = account_sale.amount.0;
+        account_sale.amount = U128(old_amount - amount);
+        self.internal_update_deposit_stats(sale, account_id, old_amount, account_sale.amount.0);
+        assert!(
+            account_sale.amount.0 == 0 || account_sale.amount.0 >= sale.min_buy,
+            "ERR_WRONG_AMOUNT"
+        );
+        sale.account_sales.insert(account_id, &VSaleAccount::Current(account_sale));
+        sale.collected_amount -= amount;
+        for (referrer_id, reward) in self.get_affiliate_rewards(account_id, amount) {
+            self.internal_remove_affiliate(sale, &referrer_id, reward);
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Withdraws part of the deposit while the subscription sale is open.
+    /// Withdrawal penalty of the sale is kept as proceeds.
+    /// Only deposits in deposit_token_id can be withdrawn.
+    pub fn withdraw_deposit(&mut self, sale_id: u64, amount: U128) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        assert!(sale.sale_type == SaleType::BySubscription, "ERR_WITHDRAW_NOT_ALLOWED");
+        assert!(!sale.cancelled, "ERR_SALE_CANCELLED");
+        sale.assert_not_finalized();
+        let timestamp = env::block_timestamp();
+        assert!(timestamp >= sale.start_date && timestamp <= sale.end_date, "ERR_SALE_DONE");
+        assert_ne!(amount.0, 0, "ERR_NOTHING_TO_WITHDRAW");
+
+        self.internal_reduce_deposit(&mut sale, &account_id, amount.0);
+        let penalty = amount.0 * sale.withdrawal_penalty.unwrap_or(0) as u128 / WITHDRAWAL_PENALTY_DENOMINATOR;
+        sale.penalty_amount += penalty;
+        let amount_to_withdraw = amount.0 - penalty;
+
+        log!("Deposit to withdraw: {}, penalty: {}", amount_to_withdraw, penalty);
+        let token_account_id = sale.deposit_token_id.clone();
+        self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+
+        self.internal_send_deposit_token(
+            sale_id,
+            account_id.clone(),
+            token_account_id.clone(),
+            amount_to_withdraw,
+            format!("Withdraw deposit {} of {}. Sale #{}", amount_to_withdraw, token_account_id, sale_id),
+        )
+            .then(ext_self::after_withdraw_deposit(
+                account_id,
+                amount,
+                U128(penalty),
+                sale_id,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_AFTER_FT_TRANSFER,
+            ))
+    }
+
+    #[private]
+    pub fn after_withdraw_deposit(&mut self, account_id: AccountId, amount: U128, penalty: U128, sale_id: u64) -> bool {
+        let promise_success = is_promise_success();
+        if promise_success {
+            Event::DepositWithdraw(vec![DepositWithdrawData {
+                sale_id,
+                account_id: &account_id,
+                amount,
+                penalty,
+            }]).emit();
+        } else {
+            let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+            let mut account_sale: SaleAccount = sale.account_sales.get(&account_id).expect("ERR_NO_DATA").into();
+            let old_amount = account_sale.amount.0;
+            account_sale.amount = U128(old_amount + amount.0);
+            self.internal_update_deposit_stats(&mut sale, &account_id, old_amount, account_sale.amount.0);
+            sale.account_sales.insert(&account_id, &VSaleAccount::Current(account_sale));
+            sale.collected_amount += amount.0;
+            sale.penalty_amount -= penalty.0;
+            let deposit_token_id = sale.deposit_token_id.clone();
+            sale.internal_rollback_near_payout(&deposit_token_id, amount.0 - penalty.0);
+            for (referrer_id, reward) in self.get_affiliate_rewards(&account_id, amount.0) {
+                self.internal_insert_affiliate(sale_id, &mut sale, &referrer_id, reward);
+            }
+            self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+            log!("Deposit withdraw for {} failed. Tokens to recharge: {}", account_id, amount.0);
+        }
+        promise_success
+    }
+}
@@ -0,0 +1,98 @@
+use near_sdk::serde::{Deserialize, Serialize};
+
+use crate::*;
+use crate::events::emit_sale_update;
+use crate::finalize::SaleStatus;
+use crate::sale::*;
+
+/// Conditions for the sale views. Empty fields match every sale.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SaleFilter {
+    /// Sale matches if it has any of the statuses.
+    pub statuses: Option<Vec<SaleStatus>>,
+    pub category: Option<String>,
+    pub tag: Option<String>,
+    pub featured: Option<bool>,
+}
+
+impl SaleFilter {
+    fn matches(&self, sale: &Sale) -> bool {
+        if let Some(statuses) = &self.statuses {
+            if !statuses.contains(&sale.get_status(env::block_timestamp())) {
+                return false;
+            }
+        }
+        if self.category.is_some() && self.category != sale.category {
+            return false;
+        }
+        if let Some(tag) = &self.tag {
+            if !sale.tags.contains(tag) {
+                return false;
+            }
+        }
+        if let Some(featured) = self.featured {
+            if featured != sale.featured {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Up to `limit` sales matching the filter, starting from `from_index` sale id.
+    /// Continue from the `sale_id` of the last returned sale + 1.
+    /// The scan runs to `num_sales`, so fewer than `limit` sales means no more sales match.
+    pub fn get_sales_filtered(&self, filter: SaleFilter, from_index: u64, limit: u64) -> Vec<SaleOutput> {
+        let mut result = vec![];
+        for sale_id in from_index..self.num_sales {
+            if result.len() as u64 >= limit {
+                break;
+            }
+            if let Some(sale) = self.sales.get(&sale_id) {
+                let sale: Sale = sale.into();
+                if filter.matches(&sale) {
+                    result.push(Contract::get_sale_output(VSale::Current(Box::new(sale)), sale_id));
+                }
+            }
+        }
+        result
+    }
+
+    pub fn get_sales_by_status(&self, status: SaleStatus, from_index: u64, limit: u64) -> Vec<SaleOutput> {
+        self.get_sales_filtered(
+            SaleFilter {
+                statuses: Some(vec![status]),
+                ..Default::default()
+            },
+            from_index,
+            limit,
+        )
+    }
+
+    #[private]
+    pub fn update_sale_tags(&mut self, sale_id: u64, tags: Vec<String>) {
+        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        sale.tags = tags;
+        self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+        emit_sale_update(sale_id, "tags");
+    }
+
+    #[private]
+    pub fn update_sale_category(&mut self, sale_id: u64, category: Option<String>) {
+        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        sale.category = category;
+        self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+        emit_sale_update(sale_id, "category");
+    }
+
+    #[private]
+    pub fn update_sale_featured(&mut self, sale_id: u64, featured: bool) {
+        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        sale.featured = featured;
+        self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+        emit_sale_update(sale_id, "featured");
+    }
+}
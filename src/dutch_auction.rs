@@ -0,0 +1,119 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::Timestamp;
+
+use crate::*;
+use crate::sale::*;
+
+/// How the price of the dutch auction falls from `price` to `floor_price` between start and end dates.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub enum PriceCurve {
+    /// Price falls at a constant rate.
+    Linear,
+    /// Price follows the linear curve, but only changes once per interval.
+    Stepped { interval: U64 },
+    /// Distance to the floor price halves every half_life. Floor is reached at the end date.
+    Exponential { half_life: U64 },
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DutchAuctionConfig {
+    /// Lowest price of the auction, in decimals of the deposit token.
+    pub floor_price: U128,
+    pub curve: PriceCurve,
+    /// Amount of distribute tokens for sale.
+    pub supply_amount: U128,
+}
+
+impl DutchAuctionConfig {
+    pub(crate) fn assert_valid(&self, start_price: Balance) {
+        assert!(self.floor_price.0 > 0, "ERR_WRONG_FLOOR_PRICE");
+        assert!(self.floor_price.0 <= start_price, "ERR_WRONG_FLOOR_PRICE");
+        assert!(self.supply_amount.0 > 0, "ERR_MUST_HAVE_SUPPLY_AMOUNT");
+        match self.curve {
+            PriceCurve::Linear => {}
+            PriceCurve::Stepped { interval } => assert!(interval.0 > 0, "ERR_WRONG_INTERVAL"),
+            PriceCurve::Exponential { half_life } => assert!(half_life.0 > 0, "ERR_WRONG_HALF_LIFE"),
+        }
+    }
+}
+
+impl Sale {
+    fn dutch_auction_config(&self) -> &DutchAuctionConfig {
+        self.dutch_auction.as_ref().expect("ERR_NO_DUTCH_AUCTION")
+    }
+
+    /// Price of the dutch auction at the given timestamp.
+    pub(crate) fn get_dutch_auction_price(&self, timestamp: Timestamp) -> Balance {
+        let config = self.dutch_auction_config();
+        let start_price = self.price;
+        let floor_price = config.floor_price.0;
+        if timestamp <= self.start_date {
+            return start_price;
+        }
+        if timestamp >= self.end_date {
+            return floor_price;
+        }
+        let duration = self.end_date - self.start_date;
+        let elapsed = timestamp - self.start_date;
+        let price_range = start_price - floor_price;
+        let linear_drop = |elapsed: u64| (
+            U256::from(price_range) * U256::from(elapsed) / U256::from(duration)
+        ).as_u128();
+
+        match config.curve {
+            PriceCurve::Linear => start_price - linear_drop(elapsed),
+            PriceCurve::Stepped { interval } => start_price - linear_drop(elapsed - elapsed % interval.0),
+            PriceCurve::Exponential { half_life } => {
+                let halvings = elapsed / half_life.0;
+                let distance = if halvings >= 128 { 0 } else { price_range >> halvings };
+                // Interpolate linearly inside of the current half_life.
+                let distance = distance - (
+                    U256::from(distance / 2) * U256::from(elapsed % half_life.0) / U256::from(half_life.0)
+                ).as_u128();
+                floor_price + distance
+            }
+        }
+    }
+
+    /// Dutch auction is done when the end date has passed or all the supply is sold.
+    pub(crate) fn is_dutch_auction_done(&self, timestamp: Timestamp) -> bool {
+        timestamp > self.end_date || self.clearing_price.is_some()
+    }
+
+    /// Price that every buyer of the dutch auction pays in the end.
+    pub(crate) fn get_dutch_auction_settlement_price(&self) -> Balance {
+        self.clearing_price.unwrap_or(self.dutch_auction_config().floor_price.0)
+    }
+
+    /// Fills the deposit at the current price.
+    /// Returns part of the deposit that was used and amount of distribute tokens bought.
+    pub(crate) fn internal_dutch_auction_fill(&mut self, amount: Balance, timestamp: Timestamp) -> (Balance, Balance) {
+        assert!(!self.is_dutch_auction_done(timestamp), "ERR_SALE_DONE");
+        let price = self.get_dutch_auction_price(timestamp);
+        let decimals = U256::from(u128::pow(10, self.distribute_token_decimals.expect("ERR_NO_TOKEN_DECIMALS") as u32));
+        let supply_amount = self.distribute_supply_amount.expect("ERR_MUST_HAVE_SUPPLY_AMOUNT");
+
+        let mut tokens = (decimals * U256::from(amount) / U256::from(price)).as_u128();
+        let mut used_amount = amount;
+        if self.sold_amount + tokens >= supply_amount {
+            tokens = supply_amount - self.sold_amount;
+            // Round up in favor of the sale, but never above the deposit.
+            used_amount = std::cmp::min(
+                amount,
+                ((U256::from(tokens) * U256::from(price) + decimals - 1) / decimals).as_u128(),
+            );
+            self.clearing_price = Some(price);
+        }
+        self.sold_amount += tokens;
+        (used_amount, tokens)
+    }
+
+    /// Value of the purchased tokens at the settlement price, rounded up.
+    pub(crate) fn get_dutch_auction_purchase_value(&self, tokens: Balance) -> Balance {
+        get_purchase_value(self, tokens, self.get_dutch_auction_settlement_price())
+    }
+}
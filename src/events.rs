@@ -0,0 +1,109 @@
+use near_sdk::json_types::U128;
+use near_sdk::serde::Serialize;
+use near_sdk::serde_json;
+use near_sdk::{log, AccountId, PublicKey};
+
+const EVENT_STANDARD: &str = "fundraiser";
+const EVENT_STANDARD_VERSION: &str = "1.0.0";
+
+/// NEP-297 events of the contract. Logged as `EVENT_JSON:{"standard", "version", "event", "data"}`.
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde", tag = "event", content = "data", rename_all = "snake_case")]
+pub(crate) enum Event<'a> {
+    SaleCreate(Vec<SaleData>),
+    SaleUpdate(Vec<SaleUpdateData<'a>>),
+    DepositAccept(Vec<DepositData<'a>>),
+    Claim(Vec<AmountData<'a>>),
+    Refund(Vec<AmountData<'a>>),
+    AffiliateReward(Vec<AmountData<'a>>),
+    AffiliateRewardClaim(Vec<AmountData<'a>>),
+    DepositWithdraw(Vec<DepositWithdrawData<'a>>),
+    Join(Vec<AccountData<'a>>),
+    LinkCreate(Vec<LinkData<'a>>),
+    LinkRemove(Vec<LinkData<'a>>),
+    AccountCreate(Vec<AccountData<'a>>),
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub(crate) struct SaleData {
+    pub sale_id: u64,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub(crate) struct SaleUpdateData<'a> {
+    pub sale_id: u64,
+    /// Name of the updated sale parameter.
+    pub update: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub(crate) struct DepositData<'a> {
+    pub sale_id: u64,
+    pub account_id: &'a AccountId,
+    pub accepted: U128,
+    pub refunded: U128,
+    /// USD value credited and the oracle rate used. Only for the sales priced in USD.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usd_value: Option<U128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate: Option<U128>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub(crate) struct AmountData<'a> {
+    pub sale_id: u64,
+    pub account_id: &'a AccountId,
+    pub amount: U128,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub(crate) struct DepositWithdrawData<'a> {
+    pub sale_id: u64,
+    pub account_id: &'a AccountId,
+    /// Deposit reduction, penalty included.
+    pub amount: U128,
+    pub penalty: U128,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub(crate) struct AccountData<'a> {
+    pub account_id: &'a AccountId,
+    pub referrer_id: &'a AccountId,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub(crate) struct LinkData<'a> {
+    pub account_id: &'a AccountId,
+    pub public_key: &'a PublicKey,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct EventLog<'a> {
+    standard: &'static str,
+    version: &'static str,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+impl Event<'_> {
+    pub(crate) fn emit(&self) {
+        let event_log = EventLog {
+            standard: EVENT_STANDARD,
+            version: EVENT_STANDARD_VERSION,
+            event: self,
+        };
+        log!("EVENT_JSON:{}", serde_json::to_string(&event_log).unwrap());
+    }
+}
+
+pub(crate) fn emit_sale_update(sale_id: u64, update: &str) {
+    Event::SaleUpdate(vec![SaleUpdateData { sale_id, update }]).emit();
+}
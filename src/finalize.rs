@@ -0,0 +1,145 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::log;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::Timestamp;
+
+use crate::*;
+use crate::events::emit_sale_update;
+use crate::sale::*;
+
+/// 10000 => all of collected_amount is used for purchases.
+pub(crate) const FILL_RATIO_DENOMINATOR: u128 = 10000;
+
+/// Results of the sale frozen by `finalize_sale`. Claims and refunds are calculated from it.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SaleResult {
+    pub collected_amount: U128,
+    /// Part of collected_amount that is used for purchases (fill_ratio / 10000).
+    pub fill_ratio: u64,
+    /// Distribute tokens owed to the buyers.
+    pub total_allocation: U128,
+    /// Deposit tokens owed back to the buyers.
+    pub total_refund: U128,
+    /// Affiliate rewards owed to the referrers, in deposit tokens.
+    pub total_affiliate_rewards: U128,
+    pub finalized_at: U64,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum SaleStatus {
+    Upcoming,
+    Active,
+    Ended,
+    Finalized,
+    /// Cancelled by the owner, deposits are refunded in full.
+    Cancelled,
+}
+
+impl Sale {
+    /// Collected amount frozen at finalization, live amount before it.
+    pub(crate) fn get_collected_amount(&self) -> Balance {
+        match &self.result {
+            Some(result) => result.collected_amount.0,
+            None => self.collected_amount,
+        }
+    }
+
+    pub(crate) fn assert_not_finalized(&self) {
+        assert!(self.result.is_none(), "ERR_SALE_FINALIZED");
+    }
+
+    /// ByAmount sale with hard limit is done once max_amount is collected.
+    fn is_cap_reached(&self) -> bool {
+        self.sale_type == SaleType::ByAmount
+            && self.hard_max_amount_limit
+            && self.collected_amount >= self.max_amount
+    }
+
+    pub(crate) fn get_status(&self, timestamp: Timestamp) -> SaleStatus {
+        if self.cancelled {
+            SaleStatus::Cancelled
+        } else if self.result.is_some() {
+            SaleStatus::Finalized
+        } else if timestamp < self.start_date {
+            SaleStatus::Upcoming
+        } else if timestamp > self.end_date || self.is_cap_reached() {
+            SaleStatus::Ended
+        } else {
+            SaleStatus::Active
+        }
+    }
+
+    fn get_result(&self, timestamp: Timestamp) -> SaleResult {
+        let collected_amount = self.collected_amount;
+        let total_refund = self.get_total_refund();
+        let fill_ratio = if collected_amount > 0 {
+            (U256::from(collected_amount - total_refund) * U256::from(FILL_RATIO_DENOMINATOR)
+                / U256::from(collected_amount)).as_u64()
+        } else {
+            0
+        };
+        let total_affiliate_rewards = if self.is_affiliate_reward_available() {
+            self.total_affiliate_rewards
+        } else {
+            0
+        };
+        let total_allocation = if self.is_full_refund() || self.distribute_token_decimals.is_none() {
+            0
+        } else {
+            self.get_total_allocation()
+        };
+        SaleResult {
+            collected_amount: U128(collected_amount),
+            fill_ratio,
+            total_allocation: U128(total_allocation),
+            total_refund: U128(total_refund),
+            total_affiliate_rewards: U128(total_affiliate_rewards),
+            finalized_at: U64(timestamp),
+        }
+    }
+}
+
+impl Contract {
+    /// Records the result of the ended sale and returns the finalized sale.
+    /// Claims finalize the sale on the first call if nobody called `finalize_sale` before.
+    pub(crate) fn internal_finalize_sale(&mut self, sale_id: u64) -> Sale {
+        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        sale.assert_not_finalized();
+        assert!(!sale.cancelled, "ERR_SALE_CANCELLED");
+        let timestamp = env::block_timestamp();
+        assert_eq!(sale.get_status(timestamp), SaleStatus::Ended, "ERR_SALE_IN_PROGRESS");
+        if sale.sale_type == SaleType::BatchAuction {
+            assert!(sale.clearing_price.is_some(), "ERR_AUCTION_NOT_SETTLED");
+        }
+
+        let result = sale.get_result(timestamp);
+        log!(
+            "Sale #{} finalized. Collected: {}, allocation: {}, refund: {}",
+            sale_id,
+            result.collected_amount.0,
+            result.total_allocation.0,
+            result.total_refund.0
+        );
+        sale.result = Some(result);
+        self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+        emit_sale_update(sale_id, "finalized");
+        self.sales.get(&sale_id).unwrap().into()
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Freezes the results of the sale after it ended or reached its cap.
+    /// Later claims and refunds are calculated from the recorded `SaleResult`.
+    pub fn finalize_sale(&mut self, sale_id: u64) -> SaleResult {
+        self.internal_finalize_sale(sale_id).result.unwrap()
+    }
+
+    pub fn get_sale_result(&self, sale_id: u64) -> Option<SaleResult> {
+        let sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        sale.result
+    }
+}
@@ -7,12 +7,44 @@ use near_sdk::collections::{LookupMap, UnorderedMap, UnorderedSet};
 use near_sdk::json_types::U128;
 use near_sdk::serde::{Deserialize, Serialize};
 
+use crate::deposit_tokens::TokenAmount;
+use crate::events::{AccountData, Event, LinkData};
+use crate::near_deposit::UncreditedNear;
 use crate::sale::VSale;
+use crate::storage::AccountStorage;
+use crate::swap::{PendingSwap, SwapAction, UnreturnedSwap};
+use crate::token_receiver::{PendingDeposit, SaleDeposit};
 
 mod sale;
+mod settlement;
+mod soft_cap;
 mod token_receiver;
+mod batch_auction;
+mod cancel;
+mod deposit_tokens;
+mod deposit_withdrawal;
+mod discovery;
+mod dutch_auction;
+mod events;
+mod finalize;
+mod lottery;
+mod native_near;
+mod near_deposit;
 mod migration_0;
 mod migration_1;
+mod migration_2;
+#[cfg(test)]
+mod mocks;
+mod portfolio;
+mod price_oracle;
+mod price_tiers;
+mod proceeds;
+mod stake_tiers;
+mod stats;
+mod storage;
+mod swap;
+mod vesting;
+mod whitelist;
 
 pub(crate) const ONE_NEAR: Balance = 10u128.pow(24);
 
@@ -35,13 +67,10 @@ const DISABLE_CLAIM_DURING_SALE: bool = false; /// TODO mostly for debug, remove
 #[ext_contract(ext_self)]
 pub trait ExtContract {
     /// Callback from checking staked balance of the given user.
-    fn on_get_account_staked_balance(
-        &mut self,
-        sale_id: u64,
-        token_id: AccountId,
-        sender_id: AccountId,
-        deposit_amount: U128,
-    ) -> PromiseOrValue<U128>;
+    fn on_get_account_staked_balance(&mut self, deposit: PendingDeposit) -> PromiseOrValue<U128>;
+
+    /// Callback from getting the USD price of the deposit token.
+    fn on_get_usd_price(&mut self, deposit: PendingDeposit, staked_amount: U128) -> U128;
 
     /// Callback after account creation.
     fn on_create_account(&mut self, new_account_id: AccountId) -> Promise;
@@ -51,6 +80,7 @@ pub trait ExtContract {
         &mut self,
         sender_id: AccountId,
         deposit_amount: U128,
+        sale_id: u64,
     ) -> PromiseOrValue<U128>;
 
     /// Callback after token claim
@@ -63,10 +93,65 @@ pub trait ExtContract {
     fn after_refund_purchase(&mut self,
                              account_id: AccountId,
                              amount_to_refund: U128,
+                             token_refund: TokenAmount,
                              sale_id: u64) -> bool;
 
     /// Callback after affiliate_rewards claim
     fn after_withdraw_affiliate_reward(&mut self, account_id: AccountId, amount: U128, sale_id: u64) -> bool;
+
+    /// Callback after proceeds withdrawal to the beneficiary
+    fn after_withdraw_proceeds(&mut self, amount: U128, token_account_id: AccountId, sale_id: u64) -> bool;
+
+    /// Callback after deposit withdrawal during the sale
+    fn after_withdraw_deposit(&mut self, account_id: AccountId, amount: U128, penalty: U128, sale_id: u64) -> bool;
+
+    /// Callback after the swap input is deposited into the exchange.
+    fn on_swap_deposit(&mut self, action: SwapAction, swap: PendingSwap) -> PromiseOrValue<U128>;
+
+    /// Callback after the swap on the exchange.
+    fn on_swap(&mut self, action: SwapAction, swap: PendingSwap) -> Promise;
+
+    /// Callback after withdrawing the input of the failed swap.
+    fn on_swap_refund(&mut self, exchange_id: AccountId, token_in: AccountId, amount_in: U128, sender_id: AccountId) -> U128;
+
+    /// Callback after withdrawing the output of the swap.
+    fn on_swap_withdraw(&mut self, token_out: AccountId, amount_out: U128, swap: PendingSwap) -> PromiseOrValue<U128>;
+
+    /// Deposit of the swapped output into the sale.
+    fn swap_sale_deposit(
+        &mut self,
+        token_id: AccountId,
+        sender_id: AccountId,
+        amount: U128,
+        sale_deposit: SaleDeposit,
+    ) -> PromiseOrValue<U128>;
+
+    /// Callback after the deposit of the swapped output.
+    fn after_swap_sale_deposit(&mut self, exchange_id: AccountId, token_id: AccountId, sender_id: AccountId, amount: U128) -> U128;
+
+    /// Callback after sending the swap tokens back to the account.
+    fn after_return_swap_token(&mut self, account_id: AccountId, exchange_id: AccountId, token_id: AccountId, amount: U128) -> bool;
+
+    /// Callback after withdrawing the unreturned swap tokens from the exchange.
+    fn on_reconcile_swap_withdraw(&mut self, account_id: AccountId, exchange_id: AccountId, token_id: AccountId, amount: U128) -> PromiseOrValue<bool>;
+
+    /// Callback after wrapping the NEAR of `deposit_near`.
+    fn on_near_deposit_wrapped(&mut self, sender_id: AccountId, deposit_amount: U128, sale_deposit: SaleDeposit) -> Promise;
+
+    /// Deposit of the wrapped NEAR into the sale.
+    fn near_sale_deposit(&mut self, sender_id: AccountId, amount: U128, sale_deposit: SaleDeposit) -> PromiseOrValue<U128>;
+
+    /// Callback after unwrapping the NEAR to return.
+    fn after_near_withdraw(&mut self, account_id: AccountId, amount: U128) -> PromiseOrValue<U128>;
+
+    /// Callback after returning NEAR to the account.
+    fn after_return_near(&mut self, account_id: AccountId, amount: U128) -> bool;
+
+    /// Callback after sending the uncredited NEAR back to the account.
+    fn after_reconcile_uncredited_near(&mut self, account_id: AccountId, near_amount: U128, wrap_amount: U128) -> bool;
+
+    /// Callback from checking staked balance of the account registering for the lottery.
+    fn on_lottery_registration_staked_balance(&mut self, sale_id: u64, account_id: AccountId);
 }
 
 #[derive(BorshSerialize, BorshDeserialize)]
@@ -148,6 +233,17 @@ pub(crate) enum StorageKey {
     Affiliates { account_id: AccountId },
     AffiliateLevels { account_id: AccountId, level: u8 },
     AccountsV1,
+    BatchAuctionDemand { sale_id: u64 },
+    LotteryParticipants { sale_id: u64 },
+    LotteryRegistered { sale_id: u64 },
+    LotteryDrawPositions { sale_id: u64 },
+    LotteryWinners { sale_id: u64 },
+    StorageAccounts,
+    AccountSaleIds { account_id: AccountId },
+    AccountsSaleIds,
+    SaleDepositCounts { sale_id: u64 },
+    UncreditedNear,
+    UnreturnedSwaps,
 }
 
 #[near_bindgen]
@@ -163,6 +259,14 @@ struct Contract {
     num_sales: u64,
     // not used anymore
     accounts_old: UnorderedMap<AccountId, AccountOld>,
+    /// NEP-145 storage deposits of the participants.
+    storage_accounts: LookupMap<AccountId, AccountStorage>,
+    /// Sales that each account deposited into.
+    account_sale_ids: LookupMap<AccountId, UnorderedSet<u64>>,
+    /// NEAR received with `deposit_near` that was neither credited nor returned, for the owner to reconcile.
+    uncredited_near: UnorderedMap<AccountId, UncreditedNear>,
+    /// Swap deposit tokens that were neither credited nor returned, for the owner to reconcile.
+    unreturned_swaps: UnorderedMap<AccountId, Vec<UnreturnedSwap>>,
 }
 
 impl Contract {
@@ -171,6 +275,7 @@ impl Contract {
         self.links.remove(&public_key);
         account.links.remove(&public_key);
         self.accounts.insert(&account_id, &VAccount::Current(account));
+        Event::LinkRemove(vec![LinkData { account_id: &account_id, public_key: &public_key }]).emit();
         Promise::new(env::current_account_id()).delete_key(public_key)
     }
 }
@@ -188,6 +293,10 @@ impl Contract {
             links: LookupMap::new(StorageKey::Links),
             num_sales: 0,
             accounts_old: UnorderedMap::new(StorageKey::AccountsV1),
+            storage_accounts: LookupMap::new(StorageKey::StorageAccounts),
+            account_sale_ids: LookupMap::new(StorageKey::AccountsSaleIds),
+            uncredited_near: UnorderedMap::new(StorageKey::UncreditedNear),
+            unreturned_swaps: UnorderedMap::new(StorageKey::UnreturnedSwaps),
         };
         this.accounts.insert(
             &this.owner_id,
@@ -214,6 +323,10 @@ impl Contract {
         account.links.insert(&public_key);
         self.accounts
             .insert(&env::predecessor_account_id(), &VAccount::Current(account));
+        Event::LinkCreate(vec![LinkData {
+            account_id: &env::predecessor_account_id(),
+            public_key: &public_key,
+        }]).emit();
         Promise::new(env::current_account_id()).add_access_key(
             public_key,
             ACCESS_KEY_ALLOWANCE,
@@ -265,6 +378,7 @@ impl Contract {
             .expect("ERR_NO_LINK");
         self.accounts
             .insert(&new_account_id, &VAccount::Current(Account::new(&new_account_id, &referrer)));
+        Event::AccountCreate(vec![AccountData { account_id: &new_account_id, referrer_id: &referrer }]).emit();
         // AUDIT: Predecessor here is `env::current_account_id()`, so it's a bug. I guess you can
         // use `referrer` here instead.
         // BEFORE AUDIT: self.internal_remove_link(env::predecessor_account_id(), env::signer_account_pk())
@@ -288,13 +402,17 @@ impl Contract {
         assert_ne!(referrer_id_unwrapped, account_id, "SELF_REFERRER");
         assert!(self.accounts.get(&account_id).is_none(), "ERR_ACCOUNT_EXISTS");
         assert_eq!(env::attached_deposit(), self.join_fee, "ERR_FEE");
+        self.assert_storage_registered(&account_id);
+        let initial_storage_usage = env::storage_usage();
         self.accounts
             .insert(&account_id, &VAccount::Current(Account::new(&account_id, &referrer_id_unwrapped)));
+        Event::Join(vec![AccountData { account_id: &account_id, referrer_id: &referrer_id_unwrapped }]).emit();
 
         // Don't save internal affiliates to save storage and gas
         if self.owner_id != referrer_id_unwrapped {
-            self.insert_affiliates(referrer_id_unwrapped, account_id);
+            self.insert_affiliates(referrer_id_unwrapped, account_id.clone());
         }
+        self.internal_charge_storage(&account_id, initial_storage_usage);
     }
 
     fn insert_affiliate_on_level(&mut self, referrer_id: &AccountId, mut referrer_account: Account, level: u8, affiliate_account_id: &AccountId) {
@@ -377,30 +495,40 @@ impl Contract {
 
 #[cfg(test)]
 mod tests {
+    use std::convert::TryInto;
     use std::str::FromStr;
 
     use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
-    use near_sdk::{PromiseResult, serde_json, testing_env};
+    use near_contract_standards::storage_management::StorageManagement;
+    use near_sdk::{CryptoHash, PromiseError, PromiseResult, serde_json, testing_env};
     use near_sdk::json_types::U64;
-    use near_sdk::test_utils::{accounts, testing_env_with_promise_results};
+    use near_sdk::test_utils::{accounts, get_logs, testing_env_with_promise_results};
     use near_sdk::test_utils::VMContextBuilder;
 
-    use crate::sale::{SaleInput, SaleMetadata, SaleType};
+    use crate::batch_auction::BatchAuctionConfig;
+    use crate::deposit_tokens::{DepositTokenRate, RATE_DENOMINATOR};
+    use crate::discovery::SaleFilter;
+    use crate::dutch_auction::{DutchAuctionConfig, PriceCurve};
+    use crate::finalize::SaleStatus;
+    use crate::lottery::{LotteryConfig, LotteryStatus};
+    use crate::mocks::{promise_result_value, MockExchange, MockPriceOracle, MockStakingPool};
+    use crate::price_oracle::UsdOracleConfig;
+    use crate::price_tiers::PriceTier;
+    use crate::sale::{Sale, SaleAccount, SaleAccountOld, SaleInput, SaleMetadata, SaleOld, SaleType, VAffiliateRewardAccount, VSaleAccount};
+    use crate::stake_tiers::StakeTier;
+    use crate::swap::{SwapAction, SwapConfig, SwapDeposit, SwapPool};
     use crate::token_receiver::SaleDeposit;
+    use crate::vesting::VestingSchedule;
+    use crate::whitelist::{verify_whitelist_proof, whitelist_leaf, WhitelistProof};
 
     use super::*;
 
-    fn contract_with_sale_info(
-        max_amount: Balance,
+    fn sale_input(
+        max_amount: Option<Balance>,
         start_date: u64,
         end_date: u64,
-    ) -> (VMContextBuilder, Contract) {
-        let mut context = VMContextBuilder::new();
-        testing_env!(context.predecessor_account_id(accounts(0)).build());
-        let join_fee = U128(1_000_000);
-        let referral_fees = vec![10, 20, 30];
-        let mut contract = Contract::new(accounts(0), join_fee, referral_fees.clone());
-        contract.create_sale(SaleInput {
+    ) -> SaleInput {
+        SaleInput {
             metadata: SaleMetadata {
                 name: "test".to_string(),
                 symbol: "TEST".to_string(),
@@ -417,21 +545,47 @@ mod tests {
             staking_contracts: vec![AccountId::new_unchecked("test.staking".to_string())],
             min_near_deposit: U128(100),
             deposit_token_id: accounts(1),
-            claim_available: true,
-            distribute_token_id: None,
-            distribute_token_decimals: None,
-            distribute_supply_amount: None,
+            claim_available: false,
+            refund_available: false,
+            distribute_token_id: Some(accounts(3)),
+            distribute_token_decimals: Some(24),
             min_buy: U128(100),
             max_buy: U128(10000),
-            max_amount: max_amount.map(|a| U128(a)),
+            max_amount: U128(max_amount.unwrap_or(0)),
             hard_max_amount_limit: max_amount.is_some(),
             start_date: U64(start_date),
             end_date: U64(end_date),
             price: U128(1000),
             whitelist_hash: None,
             limit_per_transaction: U128(100),
-            sale_type: SaleType::ByAmount
-        });
+            sale_type: SaleType::ByAmount,
+            vesting: None,
+            beneficiary_id: None,
+            dutch_auction: None,
+            batch_auction: None,
+            price_tiers: None,
+            lottery: None,
+            stake_tiers: None,
+            soft_cap: None,
+            withdrawal_penalty: None,
+            deposit_tokens: None,
+            usd_oracle: None,
+            swap: None,
+            native_near: None,
+        }
+    }
+
+    fn contract_with_sale_info(
+        max_amount: Option<Balance>,
+        start_date: u64,
+        end_date: u64,
+    ) -> (VMContextBuilder, Contract) {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let join_fee = U128(1_000_000);
+        let referral_fees = vec![10, 20, 30];
+        let mut contract = Contract::new(accounts(0), join_fee, referral_fees.clone());
+        contract.create_sale(sale_input(max_amount, start_date, end_date));
         assert_eq!(contract.get_referral_fees(), referral_fees);
         assert_eq!(contract.get_join_fee(), join_fee);
         (context, contract)
@@ -446,11 +600,16 @@ mod tests {
         contract: &mut Contract,
         account_id: AccountId,
     ) {
+        testing_env!(context
+            .predecessor_account_id(account_id.clone())
+            .attached_deposit(ONE_NEAR / 10)
+            .build());
+        contract.storage_deposit(None, None);
         testing_env!(context
             .predecessor_account_id(account_id)
             .attached_deposit(1000000)
             .build());
-        contract.join();
+        contract.join(None);
     }
 
     fn deposit(context: &mut VMContextBuilder, contract: &mut Contract, account_id: AccountId) {
@@ -461,6 +620,9 @@ mod tests {
             serde_json::to_string(&SaleDeposit {
                 sale_id: 0,
                 staking_contract: None,
+                whitelist_proof: None,
+                limit_price: None,
+                swap: None,
             })
             .unwrap(),
         );
@@ -473,11 +635,7 @@ mod tests {
         assert_eq!(contract.get_sale(0).price.0, 1000);
         assert_eq!(contract.get_sales(0, 10).len(), 1);
 
-        testing_env!(context
-            .predecessor_account_id(accounts(2))
-            .attached_deposit(1000000)
-            .build());
-        contract.join();
+        register_account(&mut context, &mut contract, accounts(2));
         assert_eq!(contract.get_account(accounts(2)).referrer, accounts(0));
 
         testing_env!(context.predecessor_account_id(accounts(1)).build());
@@ -487,6 +645,9 @@ mod tests {
             serde_json::to_string(&SaleDeposit {
                 sale_id: 0,
                 staking_contract: Some(AccountId::new_unchecked("test.staking".to_string())),
+                whitelist_proof: None,
+                limit_price: None,
+                swap: None,
             })
             .unwrap(),
         );
@@ -498,7 +659,7 @@ mod tests {
                 .build(),
             PromiseResult::Successful(vec![]),
         );
-        contract.on_get_account_staked_balance(U128(1000), 0, accounts(1), accounts(2), U128(100));
+        contract.on_get_account_staked_balance(U128(1000), pending_deposit(0, accounts(1), accounts(2), 100, 10000));
 
         assert_eq!(contract.get_sale(0).num_account_sales, 1);
         assert_eq!(contract.get_sale(0).collected_amount.0, 100);
@@ -519,6 +680,9 @@ mod tests {
             serde_json::to_string(&SaleDeposit {
                 sale_id: 0,
                 staking_contract: None,
+                whitelist_proof: None,
+                limit_price: None,
+                swap: None,
             })
             .unwrap(),
         );
@@ -528,11 +692,7 @@ mod tests {
     #[should_panic(expected = "ERR_NO_SALE")]
     fn test_no_sale() {
         let (mut context, mut contract) = contract_with_sale();
-        testing_env!(context
-            .predecessor_account_id(accounts(2))
-            .attached_deposit(1000000)
-            .build());
-        contract.join();
+        register_account(&mut context, &mut contract, accounts(2));
         testing_env!(context.predecessor_account_id(accounts(1)).build());
         contract.ft_on_transfer(
             accounts(2),
@@ -540,6 +700,9 @@ mod tests {
             serde_json::to_string(&SaleDeposit {
                 sale_id: 1,
                 staking_contract: None,
+                whitelist_proof: None,
+                limit_price: None,
+                swap: None,
             })
             .unwrap(),
         );
@@ -548,11 +711,7 @@ mod tests {
     #[test]
     fn test_create_remove_link() {
         let (mut context, mut contract) = contract_with_sale();
-        testing_env!(context
-            .predecessor_account_id(accounts(2))
-            .attached_deposit(1000000)
-            .build());
-        contract.join();
+        register_account(&mut context, &mut contract, accounts(2));
         testing_env!(context
             .predecessor_account_id(accounts(2))
             .attached_deposit(CREATE_LINK_AMOUNT)
@@ -585,8 +744,1857 @@ mod tests {
             serde_json::to_string(&SaleDeposit {
                 sale_id: 0,
                 staking_contract: None,
+                whitelist_proof: None,
+                limit_price: None,
+                swap: None,
+            })
+            .unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_whitelist_proof() {
+        testing_env!(VMContextBuilder::new().build());
+        let leaf_0 = whitelist_leaf(&accounts(2), None);
+        let leaf_1 = whitelist_leaf(&accounts(3), Some(500));
+        let (left, right) = if leaf_0 <= leaf_1 { (leaf_0, leaf_1) } else { (leaf_1, leaf_0) };
+        let root: CryptoHash = env::sha256(&[left, right].concat()).try_into().unwrap();
+
+        assert!(verify_whitelist_proof(&root, leaf_0, &[leaf_1]));
+        assert!(verify_whitelist_proof(&root, leaf_1, &[leaf_0]));
+        assert!(!verify_whitelist_proof(&root, whitelist_leaf(&accounts(3), Some(1000)), &[leaf_0]));
+        assert!(!verify_whitelist_proof(&root, whitelist_leaf(&accounts(4), None), &[leaf_1]));
+    }
+
+    /// Sale with whitelist of accounts(2) without personal max_buy and accounts(4) with max_buy 150.
+    fn contract_with_whitelist_sale() -> (VMContextBuilder, Contract, CryptoHash, CryptoHash) {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let leaf_0 = whitelist_leaf(&accounts(2), None);
+        let leaf_1 = whitelist_leaf(&accounts(4), Some(150));
+        let (left, right) = if leaf_0 <= leaf_1 { (leaf_0, leaf_1) } else { (leaf_1, leaf_0) };
+        let mut contract = Contract::new(accounts(0), U128(1_000_000), vec![10, 20, 30]);
+        let mut input = sale_input(None, 0, 1_000);
+        input.staking_contracts = vec![];
+        input.min_near_deposit = U128(0);
+        input.limit_per_transaction = U128(10000);
+        input.whitelist_hash = Some(env::sha256(&[left, right].concat()).try_into().unwrap());
+        contract.create_sale(input);
+        register_account(&mut context, &mut contract, accounts(2));
+        register_account(&mut context, &mut contract, accounts(4));
+        register_account(&mut context, &mut contract, accounts(5));
+        (context, contract, leaf_0, leaf_1)
+    }
+
+    fn whitelist_deposit(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        account_id: AccountId,
+        amount: Balance,
+        whitelist_proof: WhitelistProof,
+    ) -> Balance {
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        let unused = contract.ft_on_transfer(
+            account_id,
+            U128(amount),
+            serde_json::to_string(&SaleDeposit {
+                sale_id: 0,
+                staking_contract: None,
+                whitelist_proof: Some(whitelist_proof),
+                limit_price: None,
+                swap: None,
+            })
+            .unwrap(),
+        );
+        match unused {
+            PromiseOrValue::Value(unused) => unused.0,
+            PromiseOrValue::Promise(_) => panic!("ERR_UNEXPECTED_PROMISE"),
+        }
+    }
+
+    #[test]
+    fn test_whitelist_deposit() {
+        let (mut context, mut contract, leaf_0, leaf_1) = contract_with_whitelist_sale();
+        let proof = WhitelistProof { max_buy: None, proof: vec![leaf_1] };
+        assert_eq!(whitelist_deposit(&mut context, &mut contract, accounts(2), 300, proof), 0);
+        assert_eq!(contract.get_sale_amount(0, accounts(2)).0, 300);
+
+        // Personal max_buy of the proof caps the deposit under the max_buy of the sale.
+        let proof = WhitelistProof { max_buy: Some(U128(150)), proof: vec![leaf_0] };
+        assert_eq!(whitelist_deposit(&mut context, &mut contract, accounts(4), 300, proof), 150);
+        assert_eq!(contract.get_sale_amount(0, accounts(4)).0, 150);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_WHITELISTED")]
+    fn test_whitelist_deposit_not_whitelisted() {
+        let (mut context, mut contract, _, leaf_1) = contract_with_whitelist_sale();
+        let proof = WhitelistProof { max_buy: None, proof: vec![leaf_1] };
+        whitelist_deposit(&mut context, &mut contract, accounts(5), 300, proof);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_WHITELISTED")]
+    fn test_whitelist_deposit_wrong_max_buy() {
+        let (mut context, mut contract, leaf_0, _) = contract_with_whitelist_sale();
+        let proof = WhitelistProof { max_buy: Some(U128(1000)), proof: vec![leaf_0] };
+        whitelist_deposit(&mut context, &mut contract, accounts(4), 300, proof);
+    }
+
+    #[test]
+    fn test_vesting_schedule() {
+        let vesting = VestingSchedule {
+            tge_date: U64(1_000),
+            tge_percent: 1000,
+            cliff_duration: U64(500),
+            vesting_duration: U64(1_000),
+            vesting_interval: U64(0),
+        };
+        assert_eq!(vesting.get_vested_amount(10_000, 999), 0);
+        assert_eq!(vesting.get_vested_amount(10_000, 1_000), 1_000);
+        assert_eq!(vesting.get_vested_amount(10_000, 1_499), 1_000);
+        assert_eq!(vesting.get_vested_amount(10_000, 2_000), 5_500);
+        assert_eq!(vesting.get_vested_amount(10_000, 2_500), 10_000);
+        assert_eq!(vesting.get_vested_amount(10_000, 5_000), 10_000);
+
+        let stepped = VestingSchedule { vesting_interval: U64(300), ..vesting };
+        assert_eq!(stepped.get_vested_amount(10_000, 1_799), 1_000);
+        assert_eq!(stepped.get_vested_amount(10_000, 2_000), 3_700);
+        assert_eq!(stepped.get_vested_amount(10_000, 2_500), 10_000);
+    }
+
+    fn pending_deposit(sale_id: u64, token_id: AccountId, sender_id: AccountId, amount: Balance, max_buy: Balance) -> PendingDeposit {
+        PendingDeposit {
+            sale_id,
+            token_id,
+            sender_id,
+            amount: U128(amount),
+            max_buy: U128(max_buy),
+            limit_price: None,
+        }
+    }
+
+    fn deposit_with_stake(context: &mut VMContextBuilder, contract: &mut Contract, account_id: AccountId, amount: Balance) {
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.ft_on_transfer(
+            account_id.clone(),
+            U128(amount),
+            serde_json::to_string(&SaleDeposit {
+                sale_id: 0,
+                staking_contract: Some(AccountId::new_unchecked("test.staking".to_string())),
+                whitelist_proof: None,
+                limit_price: None,
+                swap: None,
+            })
+            .unwrap(),
+        );
+        testing_env_with_promise_results(
+            context
+                .current_account_id(accounts(0))
+                .predecessor_account_id(accounts(0))
+                .build(),
+            PromiseResult::Successful(vec![]),
+        );
+        contract.on_get_account_staked_balance(U128(1000), pending_deposit(0, accounts(1), account_id, amount, 10000));
+    }
+
+    fn fund_sale(context: &mut VMContextBuilder, contract: &mut Contract, amount: Balance) {
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.ft_on_transfer(accounts(0), U128(amount), "{\"fund_sale\": 0}".to_string());
+    }
+
+    #[test]
+    fn test_decode_sale_v1() {
+        // Layout of `VSale::Current` and `VSaleAccount::Current` before vesting.
+        #[derive(BorshSerialize)]
+        struct BaselineSale {
+            metadata: SaleMetadata,
+            staking_contracts: Vec<AccountId>,
+            min_near_deposit: Balance,
+            deposit_token_id: AccountId,
+            claim_available: bool,
+            refund_available: bool,
+            distribute_token_id: Option<AccountId>,
+            distribute_token_decimals: Option<u8>,
+            distribute_supply_amount: Option<Balance>,
+            min_buy: Balance,
+            max_buy: Balance,
+            max_amount: Balance,
+            hard_max_amount_limit: bool,
+            start_date: u64,
+            end_date: u64,
+            price: Balance,
+            whitelist_hash: Option<CryptoHash>,
+            limit_per_transaction: Balance,
+            collected_amount: Balance,
+            account_sales: UnorderedMap<AccountId, BaselineVSaleAccount>,
+            account_affiliate_rewards: UnorderedMap<AccountId, VAffiliateRewardAccount>,
+            sale_type: SaleType,
+        }
+        #[derive(BorshSerialize)]
+        #[allow(dead_code)]
+        enum BaselineVSale {
+            First(SaleOld),
+            Current(BaselineSale),
+        }
+        #[derive(BorshSerialize, BorshDeserialize)]
+        #[allow(dead_code)]
+        enum BaselineVSaleAccount {
+            First(SaleAccountOld),
+            Current(U128, U128, U128, U128, U128),
+        }
+
+        let context = VMContextBuilder::new();
+        testing_env!(context.build());
+        let input = sale_input(Some(10000), 0, 1_000);
+        let mut account_sales = UnorderedMap::new(StorageKey::AccountSales { sale_id: 0 });
+        account_sales.insert(&accounts(2), &BaselineVSaleAccount::Current(U128(100), U128(50), U128(20), U128(30), U128(0)));
+        let baseline_sale = BaselineVSale::Current(BaselineSale {
+            metadata: input.metadata,
+            staking_contracts: input.staking_contracts,
+            min_near_deposit: 100,
+            deposit_token_id: accounts(1),
+            claim_available: true,
+            refund_available: false,
+            distribute_token_id: Some(accounts(3)),
+            distribute_token_decimals: Some(24),
+            distribute_supply_amount: None,
+            min_buy: 100,
+            max_buy: 10000,
+            max_amount: 10000,
+            hard_max_amount_limit: true,
+            start_date: 0,
+            end_date: 1_000,
+            price: 1000,
+            whitelist_hash: None,
+            limit_per_transaction: 100,
+            collected_amount: 100,
+            account_sales,
+            account_affiliate_rewards: UnorderedMap::new(StorageKey::AccountAffiliateRewards { sale_id: 0 }),
+            sale_type: SaleType::ByAmount,
+        });
+
+        let v_sale = VSale::try_from_slice(&baseline_sale.try_to_vec().unwrap()).unwrap();
+        assert!(matches!(v_sale, VSale::V1(_)));
+        let sale: Sale = v_sale.into();
+        assert_eq!(sale.deposit_token_id, accounts(1));
+        assert_eq!(sale.collected_amount, 100);
+        assert!(sale.claim_available);
+        assert!(sale.sale_type == SaleType::ByAmount);
+        assert!(sale.vesting.is_none());
+        assert!(!sale.cancelled);
+        assert!(sale.stats.is_none());
+        assert_eq!(sale.near_balance, 0);
+
+        let account_sale: SaleAccount = sale.account_sales.get(&accounts(2)).unwrap().into();
+        assert_eq!(account_sale.amount.0, 100);
+        assert_eq!(account_sale.amount_to_claim.0, 50);
+        assert_eq!(account_sale.claimed.0, 20);
+        assert_eq!(account_sale.refund.0, 30);
+        assert!(account_sale.limit_price.is_none());
+        assert!(account_sale.token_deposit.is_none());
+    }
+
+    #[test]
+    fn test_fund_sale() {
+        let (mut context, mut contract) = contract_with_sale();
+        register_account(&mut context, &mut contract, accounts(2));
+        deposit_with_stake(&mut context, &mut contract, accounts(2), 100);
+
+        fund_sale(&mut context, &mut contract, ONE_NEAR / 100);
+        fund_sale(&mut context, &mut contract, ONE_NEAR / 10);
+        assert_eq!(contract.get_sale(0).distribute_escrow_amount.0, ONE_NEAR / 100 + ONE_NEAR / 10);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.update_sale_claim_available(0, true);
+        assert!(contract.get_sale(0).claim_available);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_ENOUGH_ESCROW")]
+    fn test_claim_available_without_escrow() {
+        let (mut context, mut contract) = contract_with_sale();
+        register_account(&mut context, &mut contract, accounts(2));
+        deposit_with_stake(&mut context, &mut contract, accounts(2), 100);
+
+        fund_sale(&mut context, &mut contract, ONE_NEAR / 100);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.update_sale_claim_available(0, true);
+    }
+
+    #[test]
+    fn test_dutch_auction_price() {
+        let (mut context, mut contract) = contract_with_sale();
+        let mut input = sale_input(None, 1_000, 2_000);
+        input.sale_type = SaleType::DutchAuction;
+        input.dutch_auction = Some(DutchAuctionConfig {
+            floor_price: U128(500),
+            curve: PriceCurve::Linear,
+            supply_amount: U128(ONE_NEAR),
+        });
+        let sale_id = contract.create_sale(input);
+
+        testing_env!(context.block_timestamp(1_500).build());
+        assert_eq!(contract.get_sale(sale_id).current_price.0, 750);
+        testing_env!(context.block_timestamp(2_001).build());
+        assert_eq!(contract.get_sale(sale_id).current_price.0, 500);
+    }
+
+    #[test]
+    fn test_dutch_auction_refunds() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), U128(1_000_000), vec![10, 20, 30]);
+        let mut input = sale_input(None, 0, 1_000);
+        input.sale_type = SaleType::DutchAuction;
+        input.price = U128(7);
+        input.distribute_token_decimals = Some(1);
+        input.limit_per_transaction = U128(1000);
+        input.dutch_auction = Some(DutchAuctionConfig {
+            floor_price: U128(3),
+            curve: PriceCurve::Linear,
+            supply_amount: U128(10_000),
+        });
+        contract.create_sale(input);
+
+        for (account_id, amount, timestamp) in [(accounts(2), 100, 0), (accounts(4), 101, 500), (accounts(5), 250, 750)] {
+            testing_env!(context.block_timestamp(timestamp).build());
+            register_account(&mut context, &mut contract, account_id.clone());
+            deposit_with_stake(&mut context, &mut contract, account_id, amount);
+        }
+        fund_sale(&mut context, &mut contract, 969);
+        testing_env!(context.predecessor_account_id(accounts(0)).block_timestamp(1_001).build());
+        contract.update_sale_claim_available(0, true);
+        contract.update_sale_refund_available(0, true);
+        let result = contract.finalize_sale(0);
+        assert_eq!(result.total_allocation.0, 969);
+        // 451 collected minus 290.7 rounded up for all the 969 tokens at the floor price.
+        assert_eq!(result.total_refund.0, 160);
+
+        // Purchase value of each account is rounded up: 42.6, 60.6 and 187.5.
+        let refunds: Vec<Balance> = [accounts(2), accounts(4), accounts(5)]
+            .iter()
+            .map(|account_id| claim_and_refund(&mut context, &mut contract, account_id.clone()))
+            .collect();
+        assert_eq!(refunds, vec![57, 40, 62]);
+        assert!(refunds.iter().sum::<Balance>() <= result.total_refund.0);
+    }
+
+    #[test]
+    fn test_batch_auction() {
+        let (mut context, mut contract) = contract_with_sale();
+        let mut input = sale_input(None, 0, 1_000);
+        input.staking_contracts = vec![];
+        input.min_near_deposit = U128(0);
+        input.price = U128(500);
+        input.refund_available = true;
+        input.sale_type = SaleType::BatchAuction;
+        input.batch_auction = Some(BatchAuctionConfig { supply_amount: U128(ONE_NEAR / 10) });
+        let sale_id = contract.create_sale(input);
+
+        register_account(&mut context, &mut contract, accounts(2));
+        register_account(&mut context, &mut contract, accounts(3));
+        register_account(&mut context, &mut contract, accounts(4));
+        for (account_id, limit_price) in [(accounts(2), 2000), (accounts(3), 1000), (accounts(4), 500)] {
+            testing_env!(context.predecessor_account_id(accounts(1)).build());
+            contract.ft_on_transfer(
+                account_id,
+                U128(100),
+                serde_json::to_string(&SaleDeposit {
+                    sale_id,
+                    staking_contract: None,
+                    whitelist_proof: None,
+                    limit_price: Some(U128(limit_price)),
+                    swap: None,
+                })
+                .unwrap(),
+            );
+        }
+        assert_eq!(contract.get_sale_amount(sale_id, accounts(2)).0, 100);
+        // Nothing to vest until the clearing price is known.
+        assert_eq!(contract.get_vesting(sale_id, accounts(2), None).amount_to_claim.0, 0);
+
+        testing_env!(context.block_timestamp(1_001).build());
+        assert_eq!(contract.settle_batch_auction(sale_id, 1), None);
+        assert_eq!(contract.settle_batch_auction(sale_id, 1), Some(U128(1000)));
+        assert_eq!(contract.get_sale(sale_id).clearing_price, Some(U128(1000)));
+        assert_eq!(contract.get_sale_amount(sale_id, accounts(2)).0, 50);
+
+        let account_2 = contract.get_sale_account(sale_id, accounts(2));
+        assert_eq!(account_2.amount_to_claim.0, ONE_NEAR / 20);
+        assert_eq!(account_2.refund.0, 50);
+        let account_3 = contract.get_sale_account(sale_id, accounts(3));
+        assert_eq!(account_3.amount_to_claim.0, ONE_NEAR / 20);
+        assert_eq!(account_3.refund.0, 50);
+        let account_4 = contract.get_sale_account(sale_id, accounts(4));
+        assert_eq!(account_4.amount_to_claim.0, 0);
+        assert_eq!(account_4.refund.0, 100);
+
+        contract.finalize_sale(sale_id);
+
+        // Outbid account gets the whole deposit back without a claim.
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.claim_refund(sale_id);
+        assert_eq!(contract.get_sale_account(sale_id, accounts(4)).refunded.0, 100);
+    }
+
+    #[test]
+    fn test_price_tiers() {
+        let (mut context, mut contract) = contract_with_sale();
+        let mut input = sale_input(None, 0, 1_000);
+        input.staking_contracts = vec![];
+        input.min_near_deposit = U128(0);
+        input.price_tiers = Some(vec![
+            PriceTier { amount: U128(ONE_NEAR / 10), price: U128(1000) },
+            PriceTier { amount: U128(ONE_NEAR / 5), price: U128(2000) },
+        ]);
+        let sale_id = contract.create_sale(input);
+
+        register_account(&mut context, &mut contract, accounts(2));
+        register_account(&mut context, &mut contract, accounts(3));
+        for account_id in [accounts(2), accounts(3)] {
+            testing_env!(context.predecessor_account_id(accounts(1)).build());
+            contract.ft_on_transfer(
+                account_id,
+                U128(100),
+                serde_json::to_string(&SaleDeposit {
+                    sale_id,
+                    staking_contract: None,
+                    whitelist_proof: None,
+                    limit_price: None,
+                    swap: None,
+                })
+                .unwrap(),
+            );
+        }
+
+        assert_eq!(contract.get_sale_account(sale_id, accounts(2)).amount_to_claim.0, ONE_NEAR / 10);
+        assert_eq!(contract.get_sale_account(sale_id, accounts(3)).amount_to_claim.0, ONE_NEAR / 20);
+        let sale = contract.get_sale(sale_id);
+        assert_eq!(sale.current_tier, Some(1));
+        assert_eq!(sale.current_price.0, 2000);
+    }
+
+    fn lottery_deposit(context: &mut VMContextBuilder, contract: &mut Contract, account_id: AccountId, amount: Balance) -> PromiseOrValue<U128> {
+        testing_env!(context.predecessor_account_id(accounts(1)).block_timestamp(1_000).build());
+        contract.ft_on_transfer(
+            account_id,
+            U128(amount),
+            serde_json::to_string(&SaleDeposit {
+                sale_id: 1,
+                staking_contract: None,
+                whitelist_proof: None,
+                limit_price: None,
+                swap: None,
             })
             .unwrap(),
+        )
+    }
+
+    /// Lottery sale #1 with 3 registered accounts and 2 winners drawn.
+    fn contract_with_lottery() -> (VMContextBuilder, Contract, Vec<AccountId>) {
+        let (mut context, mut contract) = contract_with_sale();
+        let mut input = sale_input(None, 1_000, 2_000);
+        input.staking_contracts = vec![];
+        input.min_near_deposit = U128(0);
+        input.min_buy = U128(0);
+        input.limit_per_transaction = U128(1_000);
+        input.sale_type = SaleType::Lottery;
+        input.lottery = Some(LotteryConfig {
+            registration_start: U64(0),
+            registration_end: U64(500),
+            num_winners: 2,
+            ticket_size: U128(100),
+        });
+        let sale_id = contract.create_sale(input);
+
+        for account_id in [accounts(2), accounts(3), accounts(4)] {
+            register_account(&mut context, &mut contract, account_id.clone());
+            contract.register_for_lottery(sale_id, None);
+            assert_eq!(contract.get_lottery_status(sale_id, account_id), LotteryStatus::Registered);
+        }
+        assert_eq!(contract.get_lottery_participants(sale_id, 0, 10).len(), 3);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).block_timestamp(501).build());
+        assert!(!contract.draw_lottery(sale_id, 1));
+        assert!(contract.draw_lottery(sale_id, 10));
+        let winners = contract.get_lottery_winners(sale_id, 0, 10);
+        (context, contract, winners)
+    }
+
+    #[test]
+    fn test_lottery() {
+        let (mut context, mut contract, winners) = contract_with_lottery();
+        let sale_id = 1;
+        assert_eq!(winners.len(), 2);
+        assert_ne!(winners[0], winners[1]);
+        let lottery = contract.get_lottery(sale_id);
+        assert!(lottery.is_drawn);
+        assert_eq!(lottery.num_drawn, 2);
+
+        let loser_id = [accounts(2), accounts(3), accounts(4)]
+            .iter()
+            .find(|account_id| !winners.contains(account_id))
+            .unwrap()
+            .clone();
+        assert_eq!(contract.get_lottery_status(sale_id, loser_id), LotteryStatus::Lost);
+        assert_eq!(contract.get_lottery_status(sale_id, winners[0].clone()), LotteryStatus::Won);
+
+        // Deposit over the ticket size buys exactly one ticket.
+        let unused = lottery_deposit(&mut context, &mut contract, winners[0].clone(), 150);
+        assert!(matches!(unused, PromiseOrValue::Value(U128(50))));
+        assert_eq!(contract.get_sale_amount(sale_id, winners[0].clone()).0, 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WRONG_TICKET_SIZE")]
+    fn test_lottery_ticket_size() {
+        let (mut context, mut contract, winners) = contract_with_lottery();
+        lottery_deposit(&mut context, &mut contract, winners[0].clone(), 99);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_LOTTERY_WINNER")]
+    fn test_lottery_loser_deposit() {
+        let (mut context, mut contract, winners) = contract_with_lottery();
+        let loser_id = [accounts(2), accounts(3), accounts(4)]
+            .iter()
+            .find(|account_id| !winners.contains(account_id))
+            .unwrap()
+            .clone();
+        lottery_deposit(&mut context, &mut contract, loser_id, 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_REGISTRATION_DONE")]
+    fn test_lottery_staked_registration_done() {
+        let (mut context, mut contract) = contract_with_sale();
+        let mut input = sale_input(None, 1_000, 2_000);
+        input.limit_per_transaction = U128(1_000);
+        input.sale_type = SaleType::Lottery;
+        input.lottery = Some(LotteryConfig {
+            registration_start: U64(0),
+            registration_end: U64(500),
+            num_winners: 2,
+            ticket_size: U128(100),
+        });
+        let sale_id = contract.create_sale(input);
+        register_account(&mut context, &mut contract, accounts(2));
+        let result = contract.register_for_lottery(sale_id, Some(AccountId::new_unchecked("test.staking".to_string())));
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        drop(result);
+
+        // Stake is returned after the registration closed.
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .current_account_id(accounts(0))
+            .block_timestamp(501)
+            .build());
+        contract.on_lottery_registration_staked_balance(U128(100), sale_id, accounts(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WRONG_AMOUNT")]
+    fn test_stake_tiers() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), U128(1_000_000), vec![10, 20, 30]);
+        let mut input = sale_input(None, 0, 1_000);
+        input.limit_per_transaction = U128(1000);
+        // Tier allocations are not capped by the global max_buy.
+        input.max_buy = U128(300);
+        input.stake_tiers = Some(vec![
+            StakeTier { staked_amount: U128(100), max_buy: U128(200) },
+            StakeTier { staked_amount: U128(1000), max_buy: U128(500) },
+        ]);
+        contract.create_sale(input);
+        assert_eq!(contract.get_stake_allocation(0, U128(999)).0, 200);
+        assert_eq!(contract.get_stake_allocation(0, U128(1000)).0, 500);
+
+        register_account(&mut context, &mut contract, accounts(2));
+        // Staked 1000: allocation of the second tier.
+        deposit_with_stake(&mut context, &mut contract, accounts(2), 500);
+        assert_eq!(contract.get_sale_amount(0, accounts(2)).0, 500);
+        deposit_with_stake(&mut context, &mut contract, accounts(2), 100);
+    }
+
+    #[test]
+    fn test_soft_cap_failed() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), U128(1_000_000), vec![10, 20, 30]);
+        let mut input = sale_input(None, 0, 1_000);
+        input.soft_cap = Some(U128(1000));
+        input.beneficiary_id = Some(accounts(0));
+        contract.create_sale(input);
+
+        register_account(&mut context, &mut contract, accounts(2));
+        deposit_with_stake(&mut context, &mut contract, accounts(2), 100);
+        assert!(!contract.is_sale_failed(0));
+
+        testing_env!(context.predecessor_account_id(accounts(2)).block_timestamp(1_001).build());
+        assert!(contract.get_sale(0).is_failed);
+        assert_eq!(contract.get_available_proceeds(0, None).0, 0);
+        contract.claim_refund(0);
+        let account_sale = contract.get_sale_account(0, accounts(2));
+        assert_eq!(account_sale.refunded.0, 100);
+    }
+
+    #[test]
+    fn test_cancel_sale() {
+        let (mut context, mut contract) = contract_with_sale();
+        register_account(&mut context, &mut contract, accounts(2));
+        register_account(&mut context, &mut contract, accounts(3));
+        deposit_with_stake(&mut context, &mut contract, accounts(2), 100);
+        deposit_with_stake(&mut context, &mut contract, accounts(3), 100);
+        deposit_with_stake(&mut context, &mut contract, accounts(3), 100);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.cancel_sale(0);
+        assert!(contract.get_sale(0).cancelled);
+        assert_eq!(contract.get_sale(0).status, SaleStatus::Cancelled);
+        assert!(contract.get_sales_by_status(SaleStatus::Active, 0, 10).is_empty());
+        assert_eq!(contract.get_sales_by_status(SaleStatus::Cancelled, 0, 10).len(), 1);
+        assert_eq!(contract.refund_sale_accounts(0, 0, 1), 1);
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.claim_refund(0);
+        assert_eq!(contract.get_sale_account(0, accounts(2)).refunded.0, 100);
+        assert_eq!(contract.get_sale_account(0, accounts(3)).refunded.0, 200);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        assert_eq!(contract.refund_sale_accounts(0, 0, 10), 0);
+    }
+
+    #[test]
+    fn test_withdraw_deposit() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), U128(1_000_000), vec![10, 20, 30]);
+        let mut input = sale_input(None, 0, 1_000);
+        input.sale_type = SaleType::BySubscription;
+        input.withdrawal_penalty = Some(1000);
+        input.limit_per_transaction = U128(10000);
+        contract.create_sale(input);
+
+        register_account(&mut context, &mut contract, accounts(2));
+        deposit_with_stake(&mut context, &mut contract, accounts(2), 5000);
+        deposit_with_stake(&mut context, &mut contract, accounts(2), 5000);
+        // Owner is the referrer on all 3 levels: 5 + 10 + 15 per deposit.
+        assert_eq!(contract.get_affiliate_account(0, accounts(0)).amount.0, 60);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.withdraw_deposit(0, U128(5000));
+        let sale = contract.get_sale(0);
+        assert_eq!(sale.collected_amount.0, 5000);
+        assert_eq!(sale.penalty_amount.0, 500);
+        assert_eq!(contract.get_sale_account(0, accounts(2)).amount.0, 5000);
+        assert_eq!(contract.get_affiliate_account(0, accounts(0)).amount.0, 30);
+
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(0)).build(),
+            PromiseResult::Successful(vec![]),
+        );
+        assert!(contract.after_withdraw_deposit(accounts(2), U128(5000), U128(500), 0));
+        assert_eq!(
+            get_logs().last().unwrap(),
+            &format!(
+                "EVENT_JSON:{{\"standard\":\"fundraiser\",\"version\":\"1.0.0\",\"event\":\"deposit_withdraw\",\"data\":[{{\"sale_id\":0,\"account_id\":\"{}\",\"amount\":\"5000\",\"penalty\":\"500\"}}]}}",
+                accounts(2)
+            )
+        );
+    }
+
+    #[test]
+    fn test_partial_fill_over_max_buy() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), U128(1_000_000), vec![10, 20, 30]);
+        let mut input = sale_input(None, 0, 1_000);
+        input.staking_contracts = vec![];
+        input.min_near_deposit = U128(0);
+        input.max_buy = U128(150);
+        contract.create_sale(input);
+
+        register_account(&mut context, &mut contract, accounts(2));
+        deposit(&mut context, &mut contract, accounts(2));
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        let unused = contract.ft_on_transfer(
+            accounts(2),
+            U128(100),
+            serde_json::to_string(&SaleDeposit {
+                sale_id: 0,
+                staking_contract: None,
+                whitelist_proof: None,
+                limit_price: None,
+                swap: None,
+            })
+            .unwrap(),
+        );
+        match unused {
+            PromiseOrValue::Value(unused) => assert_eq!(unused.0, 50),
+            PromiseOrValue::Promise(_) => panic!("ERR_UNEXPECTED_PROMISE"),
+        }
+        assert_eq!(contract.get_sale_amount(0, accounts(2)).0, 150);
+    }
+
+    #[test]
+    fn test_storage_management() {
+        let (mut context, mut contract) = contract_with_sale();
+        assert!(contract.storage_balance_of(accounts(2)).is_none());
+        register_account(&mut context, &mut contract, accounts(2));
+        let storage_balance = contract.storage_balance_of(accounts(2)).unwrap();
+        assert_eq!(storage_balance.total.0, ONE_NEAR / 10);
+        assert!(storage_balance.available.0 < ONE_NEAR / 10);
+
+        deposit_with_stake(&mut context, &mut contract, accounts(2), 100);
+        let available = contract.storage_balance_of(accounts(2)).unwrap().available.0;
+        assert!(available < storage_balance.available.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_STORAGE_NOT_REGISTERED")]
+    fn test_join_without_storage() {
+        let (mut context, mut contract) = contract_with_sale();
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(1000000)
+            .build());
+        contract.join(None);
+    }
+
+    #[test]
+    fn test_storage_grandfathered_account() {
+        let (mut context, mut contract) = contract_with_sale();
+        // Account joined before storage management without a storage deposit.
+        contract.accounts.insert(&accounts(2), &VAccount::Current(Account::new(&accounts(2), &accounts(0))));
+        deposit_with_stake(&mut context, &mut contract, accounts(2), 100);
+        assert_eq!(contract.get_sale_amount(0, accounts(2)).0, 100);
+        assert!(contract.storage_balance_of(accounts(2)).is_none());
+
+        // Storage deposit ends the grandfathering, the next deposits are charged.
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(ONE_NEAR / 10)
+            .build());
+        contract.storage_deposit(None, None);
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(0).build());
+        let sale_id = contract.create_sale(sale_input(Some(10000), 0, 1_000_000_000));
+        testing_env_with_promise_results(
+            context.current_account_id(accounts(0)).predecessor_account_id(accounts(0)).build(),
+            PromiseResult::Successful(vec![]),
+        );
+        contract.on_get_account_staked_balance(U128(1000), pending_deposit(sale_id, accounts(1), accounts(2), 100, 10000));
+        assert_eq!(contract.get_sale_amount(sale_id, accounts(2)).0, 100);
+        assert!(contract.storage_balance_of(accounts(2)).unwrap().available.0 < ONE_NEAR / 10);
+    }
+
+    #[test]
+    fn test_events() {
+        let (mut context, mut contract) = contract_with_sale();
+        register_account(&mut context, &mut contract, accounts(2));
+        assert_eq!(
+            get_logs().last().unwrap(),
+            &format!(
+                "EVENT_JSON:{{\"standard\":\"fundraiser\",\"version\":\"1.0.0\",\"event\":\"join\",\"data\":[{{\"account_id\":\"{}\",\"referrer_id\":\"{}\"}}]}}",
+                accounts(2),
+                accounts(0)
+            )
+        );
+
+        deposit_with_stake(&mut context, &mut contract, accounts(2), 100);
+        assert!(get_logs().iter().any(|log| log.starts_with("EVENT_JSON:") && log.contains("\"event\":\"deposit_accept\"")));
+    }
+
+    #[test]
+    fn test_account_portfolio() {
+        let (mut context, mut contract) = contract_with_sale();
+        let mut input = sale_input(None, 0, 1_000);
+        input.sale_type = SaleType::BySubscription;
+        input.max_amount = U128(1000);
+        contract.create_sale(input);
+
+        register_account(&mut context, &mut contract, accounts(2));
+        deposit_with_stake(&mut context, &mut contract, accounts(2), 100);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.ft_on_transfer(
+            accounts(2),
+            U128(100),
+            serde_json::to_string(&SaleDeposit {
+                sale_id: 1,
+                staking_contract: Some(AccountId::new_unchecked("test.staking".to_string())),
+                whitelist_proof: None,
+                limit_price: None,
+                swap: None,
+            })
+            .unwrap(),
+        );
+        testing_env_with_promise_results(
+            context
+                .current_account_id(accounts(0))
+                .predecessor_account_id(accounts(0))
+                .build(),
+            PromiseResult::Successful(vec![]),
+        );
+        contract.on_get_account_staked_balance(U128(1000), pending_deposit(1, accounts(1), accounts(2), 100, 10000));
+
+        assert_eq!(contract.get_account_num_sales(accounts(2)), 2);
+        let portfolio = contract.get_account_portfolio(accounts(2), 0, 10);
+        assert_eq!(portfolio.len(), 2);
+        assert_eq!(portfolio[0].sale_id, 0);
+        assert_eq!(portfolio[1].sale_id, 1);
+        assert_eq!(portfolio[1].deposited.0, 100);
+        assert_eq!(portfolio[1].allocated.0, ONE_NEAR / 10);
+        assert_eq!(portfolio[1].claimable.0, 0);
+        assert_eq!(contract.get_account_portfolio(accounts(2), 1, 10).len(), 1);
+    }
+
+    #[test]
+    fn test_account_portfolio_legacy_sale() {
+        let (mut context, mut contract) = contract_with_sale();
+        register_account(&mut context, &mut contract, accounts(2));
+        deposit_with_stake(&mut context, &mut contract, accounts(2), 100);
+
+        // Sale from before the decimals were stored, backfilled into the portfolio by the migration.
+        let mut account_sales = UnorderedMap::new(b"legacy".to_vec());
+        account_sales.insert(&accounts(2), &VSaleAccount::First(SaleAccountOld { amount: U128(500) }));
+        let input = sale_input(None, 0, 1_000);
+        contract.sales.insert(&1, &VSale::First(SaleOld {
+            metadata: input.metadata,
+            staking_contracts: vec![],
+            min_near_deposit: 0,
+            deposit_token_id: accounts(1),
+            min_buy: 100,
+            max_buy: 10000,
+            max_amount: 0,
+            hard_max_amount_limit: false,
+            start_date: 0,
+            end_date: 1_000,
+            price: 1000,
+            whitelist_hash: None,
+            limit_per_transaction: 10000,
+            collected_amount: 500,
+            account_sales,
+        }));
+        contract.num_sales = 2;
+        testing_env!(context.current_account_id(accounts(0)).predecessor_account_id(accounts(0)).build());
+        contract.migrate_portfolio(1, 0, 10);
+
+        let portfolio = contract.get_account_portfolio(accounts(2), 0, 10);
+        assert_eq!(portfolio.len(), 2);
+        assert_eq!(portfolio[0].allocated.0, ONE_NEAR / 10);
+        assert_eq!(portfolio[1].sale_id, 1);
+        assert_eq!(portfolio[1].deposited.0, 500);
+        assert_eq!(portfolio[1].allocated.0, 0);
+        assert_eq!(portfolio[1].claimable.0, 0);
+        assert_eq!(portfolio[1].refundable.0, 0);
+    }
+
+    #[test]
+    fn test_preview_settlement() {
+        let (mut context, mut contract) = contract_with_sale();
+        register_account(&mut context, &mut contract, accounts(2));
+        deposit_with_stake(&mut context, &mut contract, accounts(2), 100);
+
+        let preview = contract.preview_settlement(0, accounts(2));
+        assert_eq!(preview.amount.0, 100);
+        assert_eq!(preview.amount_to_claim.0, ONE_NEAR / 10);
+        assert_eq!(preview.refund.0, 0);
+        assert_eq!(preview.claimable.0, 0);
+        // Nothing is recorded until the claim.
+        assert_eq!(contract.get_sale_account(0, accounts(2)).amount_to_claim.0, 0);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.cancel_sale(0);
+        let preview = contract.preview_settlement(0, accounts(2));
+        assert_eq!(preview.amount_to_claim.0, 0);
+        assert_eq!(preview.refundable.0, 100);
+    }
+
+    #[test]
+    fn test_preview_settlement_without_decimals() {
+        let (mut context, mut contract) = contract_with_sale();
+        contract.create_sale(sale_input(None, 0, 1_000));
+        let mut input = sale_input(None, 0, 1_000);
+        input.price = U128(0);
+        contract.create_sale(input);
+        register_account(&mut context, &mut contract, accounts(2));
+        for sale_id in [1, 2] {
+            testing_env!(context.predecessor_account_id(accounts(1)).build());
+            drop(contract.ft_on_transfer(
+                accounts(2),
+                U128(100),
+                serde_json::to_string(&SaleDeposit {
+                    sale_id,
+                    staking_contract: Some(AccountId::new_unchecked("test.staking".to_string())),
+                    whitelist_proof: None,
+                    limit_price: None,
+                    swap: None,
+                })
+                .unwrap(),
+            ));
+            testing_env_with_promise_results(
+                context.current_account_id(accounts(0)).predecessor_account_id(accounts(0)).build(),
+                PromiseResult::Successful(vec![]),
+            );
+            contract.on_get_account_staked_balance(U128(1000), pending_deposit(sale_id, accounts(1), accounts(2), 100, 10000));
+        }
+        // Sales from before the decimals were stored don't have them.
+        let mut sale: Sale = contract.sales.get(&1).unwrap().into();
+        sale.distribute_token_decimals = None;
+        contract.sales.insert(&1, &VSale::Current(Box::new(sale)));
+
+        for sale_id in [1, 2] {
+            let preview = contract.preview_settlement(sale_id, accounts(2));
+            assert_eq!(preview.amount.0, 100);
+            assert_eq!(preview.amount_to_claim.0, 0);
+            assert_eq!(preview.refund.0, 0);
+            assert_eq!(contract.get_vesting(sale_id, accounts(2), None).amount_to_claim.0, 0);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_SALE_FINALIZED")]
+    fn test_finalize_sale() {
+        let (mut context, mut contract) = contract_with_sale();
+        register_account(&mut context, &mut contract, accounts(2));
+        deposit_with_stake(&mut context, &mut contract, accounts(2), 100);
+        assert_eq!(contract.get_sale(0).status, SaleStatus::Active);
+
+        testing_env!(context.block_timestamp(1_000_000_001).build());
+        assert_eq!(contract.get_sale(0).status, SaleStatus::Ended);
+        let result = contract.finalize_sale(0);
+        assert_eq!(result.collected_amount.0, 100);
+        assert_eq!(result.fill_ratio, 10000);
+        assert_eq!(result.total_allocation.0, ONE_NEAR / 10);
+        assert_eq!(result.total_refund.0, 0);
+        assert_eq!(result.total_affiliate_rewards.0, 0);
+        assert_eq!(contract.get_sale(0).status, SaleStatus::Finalized);
+        assert_eq!(contract.preview_settlement(0, accounts(2)).amount_to_claim.0, ONE_NEAR / 10);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.update_sale_dates(0, U64(0), U64(2_000_000_000));
+    }
+
+    fn contract_with_ended_sale() -> (VMContextBuilder, Contract) {
+        let (mut context, mut contract) = contract_with_sale();
+        register_account(&mut context, &mut contract, accounts(2));
+        deposit_with_stake(&mut context, &mut contract, accounts(2), 100);
+        fund_sale(&mut context, &mut contract, ONE_NEAR / 10);
+        testing_env!(context.predecessor_account_id(accounts(0)).block_timestamp(1_000_000_001).build());
+        contract.update_sale_claim_available(0, true);
+        (context, contract)
+    }
+
+    #[test]
+    fn test_claim_purchase() {
+        let (mut context, mut contract) = contract_with_ended_sale();
+        contract.finalize_sale(0);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.claim_purchase(0);
+        assert_eq!(contract.get_sale_account(0, accounts(2)).claimed.0, ONE_NEAR / 10);
+        // Claim event is emitted once the transfer succeeded.
+        assert!(!get_logs().iter().any(|log| log.contains("\"event\":\"claim\"")));
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(0)).build(),
+            PromiseResult::Failed,
+        );
+        assert!(!contract.after_withdraw_purchase(accounts(2), U128(ONE_NEAR / 10), 0));
+        assert!(!get_logs().iter().any(|log| log.contains("\"event\":\"claim\"")));
+        assert_eq!(contract.get_sale_account(0, accounts(2)).claimed.0, 0);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.claim_purchase(0);
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(0)).build(),
+            PromiseResult::Successful(vec![]),
+        );
+        assert!(contract.after_withdraw_purchase(accounts(2), U128(ONE_NEAR / 10), 0));
+        assert!(get_logs().iter().any(|log| log.contains("\"event\":\"claim\"")));
+    }
+
+    #[test]
+    fn test_claim_purchase_finalizes_sale() {
+        let (mut context, mut contract) = contract_with_ended_sale();
+        assert!(contract.get_sale_result(0).is_none());
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        drop(contract.claim_purchase(0));
+        let result = contract.get_sale_result(0).unwrap();
+        assert_eq!(result.total_allocation.0, ONE_NEAR / 10);
+        assert_eq!(result.finalized_at.0, 1_000_000_001);
+        assert_eq!(contract.get_sale_account(0, accounts(2)).claimed.0, ONE_NEAR / 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_SALE_IN_PROGRESS")]
+    fn test_claim_purchase_in_progress() {
+        let (mut context, mut contract) = contract_with_sale();
+        register_account(&mut context, &mut contract, accounts(2));
+        deposit_with_stake(&mut context, &mut contract, accounts(2), 100);
+        fund_sale(&mut context, &mut contract, ONE_NEAR / 10);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.update_sale_claim_available(0, true);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.claim_purchase(0);
+    }
+
+    /// Oversubscribed subscription sale: 1000 and 500 for 1000 tokens, priced 1:1.
+    fn contract_with_subscription_sale() -> (VMContextBuilder, Contract) {
+        contract_with_vested_subscription_sale(None)
+    }
+
+    fn contract_with_vested_subscription_sale(vesting: Option<VestingSchedule>) -> (VMContextBuilder, Contract) {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), U128(1_000_000), vec![10, 20, 30]);
+        let mut input = sale_input(None, 0, 1_000);
+        input.sale_type = SaleType::BySubscription;
+        input.max_amount = U128(1000);
+        input.distribute_token_decimals = Some(3);
+        input.limit_per_transaction = U128(10000);
+        input.beneficiary_id = Some(accounts(5));
+        input.vesting = vesting;
+        contract.create_sale(input);
+
+        register_account(&mut context, &mut contract, accounts(2));
+        deposit_with_stake(&mut context, &mut contract, accounts(2), 1000);
+        register_account(&mut context, &mut contract, accounts(4));
+        deposit_with_stake(&mut context, &mut contract, accounts(4), 500);
+        fund_sale(&mut context, &mut contract, 1000);
+        testing_env!(context.predecessor_account_id(accounts(0)).block_timestamp(1_001).build());
+        contract.update_sale_claim_available(0, true);
+        contract.update_sale_refund_available(0, true);
+        contract.finalize_sale(0);
+        (context, contract)
+    }
+
+    fn claim_and_refund(context: &mut VMContextBuilder, contract: &mut Contract, account_id: AccountId) -> Balance {
+        testing_env!(context.predecessor_account_id(account_id.clone()).build());
+        drop(contract.claim_purchase(0));
+        testing_env!(context.predecessor_account_id(account_id.clone()).build());
+        drop(contract.claim_refund(0));
+        contract.get_sale_account(0, account_id).refunded.0
+    }
+
+    #[test]
+    fn test_claim_refund_before_tge() {
+        let (mut context, mut contract) = contract_with_vested_subscription_sale(Some(VestingSchedule {
+            tge_date: U64(5_000),
+            tge_percent: 1000,
+            cliff_duration: U64(0),
+            vesting_duration: U64(1_000),
+            vesting_interval: U64(0),
+        }));
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        drop(contract.claim_refund(0));
+        let account_sale = contract.get_sale_account(0, accounts(2));
+        assert_eq!(account_sale.refunded.0, 334);
+        assert_eq!(account_sale.claimed.0, 0);
+        assert_eq!(contract.get_vesting(0, accounts(2), None).vested.0, 0);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).block_timestamp(5_000).build());
+        drop(contract.claim_purchase(0));
+        assert_eq!(contract.get_sale_account(0, accounts(2)).claimed.0, 66);
+    }
+
+    #[test]
+    fn test_withdraw_proceeds() {
+        let (mut context, mut contract) = contract_with_subscription_sale();
+        // Refunds of 334 and 167 are rounded down per account, over collected - max_amount.
+        assert_eq!(contract.get_sale_result(0).unwrap().total_refund.0, 506);
+        assert_eq!(contract.get_sale_result(0).unwrap().total_affiliate_rewards.0, 8);
+        assert_eq!(contract.get_sale_result(0).unwrap().fill_ratio, 6626);
+        assert_eq!(contract.get_available_proceeds(0, None).0, 986);
+
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        drop(contract.withdraw_proceeds(0, None));
+        assert_eq!(contract.get_available_proceeds(0, None).0, 0);
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(0)).build(),
+            PromiseResult::Failed,
+        );
+        assert!(!contract.after_withdraw_proceeds(U128(986), accounts(1), 0));
+        assert_eq!(contract.get_available_proceeds(0, None).0, 986);
+
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        drop(contract.withdraw_proceeds(0, None));
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(0)).build(),
+            PromiseResult::Successful(vec![]),
+        );
+        assert!(contract.after_withdraw_proceeds(U128(986), accounts(1), 0));
+        assert_eq!(contract.get_available_proceeds(0, None).0, 0);
+
+        let refunded = claim_and_refund(&mut context, &mut contract, accounts(2))
+            + claim_and_refund(&mut context, &mut contract, accounts(4));
+        assert_eq!(refunded, 501);
+        assert_eq!(contract.get_available_proceeds(0, None).0, 0);
+    }
+
+    #[test]
+    fn test_subscription_refund_bound() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), U128(1_000_000), vec![10, 20, 30]);
+        let mut input = sale_input(None, 0, 1_000);
+        input.sale_type = SaleType::BySubscription;
+        input.price = U128(7);
+        input.max_amount = U128(1000);
+        input.distribute_token_decimals = Some(1);
+        input.limit_per_transaction = U128(10000);
+        contract.create_sale(input);
+        for (account_id, amount) in [(accounts(2), 300), (accounts(4), 410), (accounts(5), 555)] {
+            register_account(&mut context, &mut contract, account_id.clone());
+            deposit_with_stake(&mut context, &mut contract, account_id, amount);
+        }
+        fund_sale(&mut context, &mut contract, 700);
+        testing_env!(context.predecessor_account_id(accounts(0)).block_timestamp(1_001).build());
+        contract.update_sale_claim_available(0, true);
+        contract.update_sale_refund_available(0, true);
+        let result = contract.finalize_sale(0);
+
+        let refunds: Vec<Balance> = [accounts(2), accounts(4), accounts(5)]
+            .iter()
+            .map(|account_id| claim_and_refund(&mut context, &mut contract, account_id.clone()))
+            .collect();
+        // Allocations of 165, 226 and 306 tokens out of 700 are worth 115, 158 and 214.
+        assert_eq!(refunds, vec![185, 252, 341]);
+        // 1265 collected, purchases are at least ((1807 - 3) * 700 / 1807 - 3) * 7 / 10 - 3 = 483.
+        assert_eq!(result.total_refund.0, 782);
+        assert!(refunds.iter().sum::<Balance>() <= result.total_refund.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOTHING_TO_WITHDRAW")]
+    fn test_withdraw_proceeds_twice() {
+        let (mut context, mut contract) = contract_with_subscription_sale();
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        drop(contract.withdraw_proceeds(0, None));
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        drop(contract.withdraw_proceeds(0, None));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_ALLOWED")]
+    fn test_withdraw_proceeds_not_beneficiary() {
+        let (mut context, mut contract) = contract_with_subscription_sale();
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.withdraw_proceeds(0, None);
+    }
+
+    #[test]
+    fn test_sales_filtered() {
+        let (mut context, mut contract) = contract_with_sale();
+        contract.create_sale(sale_input(Some(10000), 2_000, 3_000));
+        contract.create_sale(sale_input(Some(10000), 2_000, 3_000));
+        contract.update_sale_tags(2, vec!["gaming".to_string()]);
+        contract.update_sale_category(2, Some("ido".to_string()));
+        contract.update_sale_featured(2, true);
+
+        testing_env!(context.block_timestamp(1_000).build());
+        let sales = contract.get_sales_by_status(SaleStatus::Upcoming, 0, 10);
+        assert_eq!(sales.iter().map(|sale| sale.sale_id.unwrap()).collect::<Vec<_>>(), vec![1, 2]);
+        let sales = contract.get_sales_by_status(SaleStatus::Upcoming, 0, 1);
+        assert_eq!(sales[0].sale_id, Some(1));
+        let sales = contract.get_sales_by_status(SaleStatus::Upcoming, 2, 10);
+        assert_eq!(sales[0].sale_id, Some(2));
+        assert_eq!(contract.get_sales_by_status(SaleStatus::Active, 0, 10).len(), 1);
+
+        let sales = contract.get_sales_filtered(
+            SaleFilter {
+                tag: Some("gaming".to_string()),
+                featured: Some(true),
+                ..Default::default()
+            },
+            0,
+            10,
+        );
+        assert_eq!(sales.len(), 1);
+        assert_eq!(sales[0].category, Some("ido".to_string()));
+
+        testing_env!(context.block_timestamp(3_001).build());
+        let sales = contract.get_sales_filtered(
+            SaleFilter {
+                statuses: Some(vec![SaleStatus::Ended, SaleStatus::Finalized]),
+                ..Default::default()
+            },
+            0,
+            10,
+        );
+        assert_eq!(sales.len(), 2);
+    }
+
+    #[test]
+    fn test_sale_stats() {
+        let (mut context, mut contract) = contract_with_sale();
+        register_account(&mut context, &mut contract, accounts(2));
+        register_account(&mut context, &mut contract, accounts(4));
+        testing_env!(context.predecessor_account_id(accounts(5)).attached_deposit(ONE_NEAR / 10).build());
+        contract.storage_deposit(None, None);
+        testing_env!(context.predecessor_account_id(accounts(5)).attached_deposit(1000000).build());
+        contract.join(Some(accounts(2)));
+
+        deposit_with_stake(&mut context, &mut contract, accounts(2), 100);
+        for _ in 0..3 {
+            deposit_with_stake(&mut context, &mut contract, accounts(4), 100);
+        }
+        deposit_with_stake(&mut context, &mut contract, accounts(5), 100);
+        let stats = contract.get_sale_stats(0);
+        assert_eq!(stats.num_participants, 3);
+        assert_eq!(stats.largest_deposit.0, 300);
+        assert_eq!(stats.median_deposit.0, 100);
+
+        deposit_with_stake(&mut context, &mut contract, accounts(5), 100);
+        let stats = contract.get_sale_stats(0);
+        assert_eq!(stats.median_deposit.0, 200);
+        assert_eq!(stats.mean_deposit.0, 200);
+        assert_eq!(stats.referred_amount.0, 200);
+        assert_eq!(stats.total_claimed.0, 0);
+    }
+
+    #[test]
+    fn test_multiple_deposit_tokens() {
+        let usdt = AccountId::new_unchecked("usdt.near".to_string());
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), U128(1_000_000), vec![10, 20, 30]);
+        let mut input = sale_input(Some(10000), 0, 1_000_000_000);
+        input.deposit_tokens = Some(vec![DepositTokenRate { token_id: usdt.clone(), rate: U128(RATE_DENOMINATOR / 2) }]);
+        contract.create_sale(input);
+        register_account(&mut context, &mut contract, accounts(2));
+        register_account(&mut context, &mut contract, accounts(4));
+        deposit_with_stake(&mut context, &mut contract, accounts(2), 100);
+
+        // 201 USDT is worth 100 units, 1 USDT is returned.
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(0)).build(),
+            PromiseResult::Successful(vec![]),
+        );
+        let unused = contract.on_get_account_staked_balance(U128(1000), pending_deposit(0, usdt.clone(), accounts(4), 201, 10000));
+        assert!(matches!(unused, PromiseOrValue::Value(U128(1))));
+        assert_eq!(contract.get_sale(0).collected_amount.0, 200);
+        assert_eq!(contract.get_sale_account(0, accounts(4)).amount.0, 100);
+        let deposit_token = &contract.get_sale_deposit_tokens(0)[0];
+        assert_eq!(deposit_token.collected_amount.0, 200);
+        assert_eq!(deposit_token.collected_units.0, 100);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.cancel_sale(0);
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.claim_refund(0);
+        assert_eq!(contract.get_sale_deposit_tokens(0)[0].refunded_amount.0, 200);
+        assert_eq!(contract.get_sale_account(0, accounts(4)).refunded.0, 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WRONG_TOKEN")]
+    fn test_deposit_token_after_primary_token() {
+        let usdt = AccountId::new_unchecked("usdt.near".to_string());
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), U128(1_000_000), vec![10, 20, 30]);
+        let mut input = sale_input(Some(10000), 0, 1_000_000_000);
+        input.deposit_tokens = Some(vec![DepositTokenRate { token_id: usdt.clone(), rate: U128(RATE_DENOMINATOR / 2) }]);
+        contract.create_sale(input);
+        register_account(&mut context, &mut contract, accounts(2));
+        deposit_with_stake(&mut context, &mut contract, accounts(2), 100);
+
+        // Account paid in deposit_token_id first, so the second token is rejected.
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(0)).build(),
+            PromiseResult::Successful(vec![]),
+        );
+        contract.on_get_account_staked_balance(U128(1000), pending_deposit(0, usdt, accounts(2), 200, 10000));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WRONG_TOKEN")]
+    fn test_deposit_two_secondary_tokens() {
+        let usdt = AccountId::new_unchecked("usdt.near".to_string());
+        let usdc = AccountId::new_unchecked("usdc.near".to_string());
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), U128(1_000_000), vec![10, 20, 30]);
+        let mut input = sale_input(Some(10000), 0, 1_000_000_000);
+        input.deposit_tokens = Some(vec![
+            DepositTokenRate { token_id: usdt.clone(), rate: U128(RATE_DENOMINATOR) },
+            DepositTokenRate { token_id: usdc.clone(), rate: U128(RATE_DENOMINATOR) },
+        ]);
+        contract.create_sale(input);
+        register_account(&mut context, &mut contract, accounts(2));
+
+        testing_env_with_promise_results(
+            context.current_account_id(accounts(0)).predecessor_account_id(accounts(0)).build(),
+            PromiseResult::Successful(vec![]),
+        );
+        contract.on_get_account_staked_balance(U128(1000), pending_deposit(0, usdt, accounts(2), 100, 10000));
+        testing_env_with_promise_results(
+            context.current_account_id(accounts(0)).predecessor_account_id(accounts(0)).build(),
+            PromiseResult::Successful(vec![]),
+        );
+        contract.on_get_account_staked_balance(U128(1000), pending_deposit(0, usdc, accounts(2), 100, 10000));
+    }
+
+    fn contract_with_usd_sale() -> (VMContextBuilder, Contract) {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), U128(1_000_000), vec![10, 20, 30]);
+        let mut input = sale_input(Some(10000), 0, 1_000_000_000);
+        input.staking_contracts = vec![];
+        input.min_near_deposit = U128(0);
+        input.limit_per_transaction = U128(1000);
+        input.usd_oracle = Some(UsdOracleConfig {
+            oracle_id: AccountId::new_unchecked("oracle.near".to_string()),
+            max_price_age: U64(1_000),
+        });
+        contract.create_sale(input);
+        register_account(&mut context, &mut contract, accounts(2));
+        deposit(&mut context, &mut contract, accounts(2));
+        (context, contract)
+    }
+
+    #[test]
+    fn test_usd_sale() {
+        let (mut context, mut contract) = contract_with_usd_sale();
+        let mut oracle = MockPriceOracle::new();
+        oracle.set_price(accounts(1), 2, 0, 0);
+        testing_env!(context.block_timestamp(500).predecessor_account_id(accounts(0)).build());
+        // 60 tokens at 2 USD units each are over max_buy of 100, 10 tokens are returned.
+        let unused = contract.on_get_usd_price(oracle.get_price(&accounts(1)), pending_deposit(0, accounts(1), accounts(2), 60, 100), U128(0));
+        assert_eq!(unused.0, 10);
+        assert_eq!(contract.get_sale(0).collected_amount.0, 100);
+        let sale_account = contract.get_sale_account(0, accounts(2));
+        assert_eq!(sale_account.amount.0, 100);
+        let token_deposit = sale_account.token_deposit.unwrap();
+        assert_eq!(token_deposit.amount.0, 50);
+        assert_eq!(token_deposit.usd_deposits.len(), 1);
+        assert_eq!(token_deposit.usd_deposits[0].rate.0, 2 * RATE_DENOMINATOR);
+        assert_eq!(token_deposit.usd_deposits[0].usd_amount.0, 100);
+        assert_eq!(token_deposit.usd_deposits[0].timestamp.0, 500);
+        let deposit_token = &contract.get_sale_deposit_tokens(0)[0];
+        assert_eq!(deposit_token.token_id, accounts(1));
+        assert_eq!(deposit_token.rate.0, 2 * RATE_DENOMINATOR);
+
+        // Next deposit is valued at the updated price.
+        oracle.set_price(accounts(1), 25, 1, 600);
+        register_account(&mut context, &mut contract, accounts(3));
+        testing_env!(context.block_timestamp(700).predecessor_account_id(accounts(0)).build());
+        let unused = contract.on_get_usd_price(oracle.get_price(&accounts(1)), pending_deposit(0, accounts(1), accounts(3), 40, 10000), U128(0));
+        assert_eq!(unused.0, 0);
+        assert_eq!(contract.get_sale_account(0, accounts(3)).amount.0, 100);
+        assert_eq!(contract.get_sale_deposit_tokens(0)[0].rate.0, 5 * RATE_DENOMINATOR / 2);
+        assert_eq!(contract.get_sale(0).collected_amount.0, 200);
+        // Rate of the first deposit stays recorded in its account.
+        let usd_deposit = &contract.get_sale_account(0, accounts(2)).token_deposit.unwrap().usd_deposits[0];
+        assert_eq!(usd_deposit.rate.0, 2 * RATE_DENOMINATOR);
+        let usd_deposit = &contract.get_sale_account(0, accounts(3)).token_deposit.unwrap().usd_deposits[0];
+        assert_eq!(usd_deposit.amount.0, 40);
+        assert_eq!(usd_deposit.usd_amount.0, 100);
+    }
+
+    #[test]
+    fn test_usd_sale_with_staking() {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), U128(1_000_000), vec![10, 20, 30]);
+        let mut input = sale_input(Some(10000), 0, 1_000_000_000);
+        input.limit_per_transaction = U128(1000);
+        input.usd_oracle = Some(UsdOracleConfig {
+            oracle_id: AccountId::new_unchecked("oracle.near".to_string()),
+            max_price_age: U64(1_000),
+        });
+        contract.create_sale(input);
+        register_account(&mut context, &mut contract, accounts(2));
+        let mut staking_pool = MockStakingPool::new();
+        staking_pool.stake(accounts(2), 1000);
+        let mut oracle = MockPriceOracle::new();
+        oracle.set_price(accounts(1), 4, 0, 0);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).block_timestamp(100).build());
+        let result = contract.ft_on_transfer(
+            accounts(2),
+            U128(30),
+            serde_json::to_string(&SaleDeposit {
+                sale_id: 0,
+                staking_contract: Some(AccountId::new_unchecked("test.staking".to_string())),
+                whitelist_proof: None,
+                limit_price: None,
+                swap: None,
+            })
+            .unwrap(),
+        );
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        drop(result);
+
+        // Staking pool answers, the callback asks the oracle for the price.
+        let staked_result = staking_pool.get_account_staked_balance_result(&accounts(2));
+        let staked_amount: U128 = promise_result_value(&staked_result);
+        testing_env_with_promise_results(
+            context.current_account_id(accounts(0)).predecessor_account_id(accounts(0)).build(),
+            staked_result,
+        );
+        let result = contract.on_get_account_staked_balance(staked_amount, pending_deposit(0, accounts(1), accounts(2), 30, 10000));
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        drop(result);
+        assert_eq!(contract.get_sale_amount(0, accounts(2)).0, 0);
+
+        let price_result = oracle.get_price_result(&accounts(1));
+        let price = promise_result_value(&price_result);
+        testing_env_with_promise_results(
+            context.current_account_id(accounts(0)).predecessor_account_id(accounts(0)).build(),
+            price_result,
+        );
+        let unused = contract.on_get_usd_price(price, pending_deposit(0, accounts(1), accounts(2), 30, 10000), staked_amount);
+        assert_eq!(unused.0, 0);
+        let sale_account = contract.get_sale_account(0, accounts(2));
+        assert_eq!(sale_account.amount.0, 120);
+        let usd_deposit = &sale_account.token_deposit.unwrap().usd_deposits[0];
+        assert_eq!(usd_deposit.rate.0, 4 * RATE_DENOMINATOR);
+        assert_eq!(usd_deposit.usd_amount.0, 120);
+        assert_eq!(usd_deposit.timestamp.0, 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_STALE_PRICE")]
+    fn test_usd_sale_stale_price() {
+        let (mut context, mut contract) = contract_with_usd_sale();
+        let mut oracle = MockPriceOracle::new();
+        oracle.set_price(accounts(1), 2, 0, 0);
+        testing_env!(context.block_timestamp(1_001).predecessor_account_id(accounts(0)).build());
+        contract.on_get_usd_price(oracle.get_price(&accounts(1)), pending_deposit(0, accounts(1), accounts(2), 50, 10000), U128(0));
+    }
+
+    fn contract_with_swap_sale() -> (VMContextBuilder, Contract) {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), U128(1_000_000), vec![10, 20, 30]);
+        let mut input = sale_input(Some(10000), 0, 1_000_000_000);
+        input.staking_contracts = vec![];
+        input.min_near_deposit = U128(0);
+        input.limit_per_transaction = U128(1000);
+        input.swap = Some(SwapConfig {
+            exchange_id: AccountId::new_unchecked("exchange.near".to_string()),
+            pools: vec![SwapPool { token_id: accounts(4), pool_id: 7 }],
+        });
+        contract.create_sale(input);
+        register_account(&mut context, &mut contract, accounts(2));
+        (context, contract)
+    }
+
+    fn swap_sale_deposit(swap: Option<SwapDeposit>) -> SaleDeposit {
+        SaleDeposit {
+            sale_id: 0,
+            staking_contract: None,
+            whitelist_proof: None,
+            limit_price: None,
+            swap,
+        }
+    }
+
+    fn pending_swap(exchange_id: AccountId) -> PendingSwap {
+        PendingSwap {
+            exchange_id,
+            sender_id: accounts(2),
+            sale_deposit: swap_sale_deposit(None),
+        }
+    }
+
+    fn swap_action(amount_in: Balance) -> SwapAction {
+        SwapAction {
+            pool_id: 7,
+            token_in: accounts(4),
+            amount_in: Some(U128(amount_in)),
+            token_out: accounts(1),
+            min_amount_out: U128(100),
+        }
+    }
+
+    fn mock_exchange() -> MockExchange {
+        let mut exchange = MockExchange::new();
+        exchange.add_pool(7, accounts(4), 10_000, accounts(1), 6_000);
+        exchange
+    }
+
+    #[test]
+    fn test_swap_deposit() {
+        let (mut context, mut contract) = contract_with_swap_sale();
+        let exchange_id = AccountId::new_unchecked("exchange.near".to_string());
+        let mut exchange = mock_exchange();
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        let result = contract.ft_on_transfer(
+            accounts(2),
+            U128(500),
+            serde_json::to_string(&swap_sale_deposit(Some(SwapDeposit { min_amount_out: U128(100) }))).unwrap(),
+        );
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        drop(result);
+
+        // Exchange accepted the whole input, the swap gives 500 * 6000 / 10500 of the deposit token.
+        let used_amount = exchange.deposit(&accounts(4), U128(500));
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let result = contract.on_swap_deposit(Ok(used_amount), swap_action(500), pending_swap(exchange_id.clone()));
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        drop(result);
+        let amount_out = exchange.swap(vec![swap_action(500)]);
+        assert!(matches!(amount_out, Ok(U128(285))));
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.on_swap(amount_out, swap_action(500), pending_swap(exchange_id.clone()));
+
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(0)).build(),
+            exchange.withdraw(&accounts(1), U128(285)),
+        );
+        let result = contract.on_swap_withdraw(accounts(1), U128(285), pending_swap(exchange_id.clone()));
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        drop(result);
+        assert_eq!(exchange.get_deposit(&accounts(1)), 0);
+        assert_eq!(exchange.get_deposit(&accounts(4)), 0);
+
+        // Only the output is credited to the sale.
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let unused = contract.swap_sale_deposit(accounts(1), accounts(2), U128(285), swap_sale_deposit(None));
+        assert!(matches!(unused, PromiseOrValue::Value(U128(0))));
+        assert_eq!(contract.get_sale(0).collected_amount.0, 285);
+        assert_eq!(contract.get_sale_account(0, accounts(2)).amount.0, 285);
+
+        // Output that was not accepted is sent back, the input is used in full.
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        assert_eq!(contract.after_swap_sale_deposit(Ok(U128(50)), exchange_id.clone(), accounts(1), accounts(2), U128(285)).0, 0);
+        assert!(get_logs().iter().any(|log| log.contains("Swap output to return to charlie: 50")));
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        assert_eq!(contract.after_swap_sale_deposit(Err(PromiseError::Failed), exchange_id, accounts(1), accounts(2), U128(285)).0, 0);
+        assert!(get_logs().iter().any(|log| log.contains("Swap output to return to charlie: 285")));
+    }
+
+    #[test]
+    fn test_swap_deposit_failed() {
+        let (mut context, mut contract) = contract_with_swap_sale();
+        let exchange_id = AccountId::new_unchecked("exchange.near".to_string());
+        let mut exchange = mock_exchange();
+        // Exchange didn't accept the input: it is returned by the input token.
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let result = contract.on_swap_deposit(Ok(U128(0)), swap_action(500), pending_swap(exchange_id.clone()));
+        assert!(matches!(result, PromiseOrValue::Value(U128(500))));
+
+        // Swap under min_amount_out fails: the input is withdrawn and returned.
+        let used_amount = exchange.deposit(&accounts(4), U128(500));
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let result = contract.on_swap_deposit(Ok(used_amount), swap_action(500), pending_swap(exchange_id.clone()));
+        drop(result);
+        let mut action = swap_action(500);
+        action.min_amount_out = U128(300);
+        let amount_out = exchange.swap(vec![action]);
+        assert!(amount_out.is_err());
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.on_swap(amount_out, swap_action(500), pending_swap(exchange_id.clone()));
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(0)).build(),
+            exchange.withdraw(&accounts(4), U128(500)),
+        );
+        assert_eq!(contract.on_swap_refund(exchange_id.clone(), accounts(4), U128(500), accounts(2)).0, 500);
+        assert!(contract.get_unreturned_swaps(accounts(2)).is_empty());
+
+        // Input that can't be withdrawn stays on the exchange and is recorded.
+        exchange.deposit(&accounts(4), U128(500));
+        exchange.withdraw_fails = true;
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(0)).build(),
+            exchange.withdraw(&accounts(4), U128(500)),
+        );
+        assert_eq!(contract.on_swap_refund(exchange_id, accounts(4), U128(500), accounts(2)).0, 0);
+        assert_eq!(contract.get_sale(0).collected_amount.0, 0);
+        let unreturned_swaps = contract.get_unreturned_swaps(accounts(2));
+        assert_eq!(unreturned_swaps[0].token_id, accounts(4));
+        assert_eq!(unreturned_swaps[0].exchange_amount.0, 500);
+        assert_eq!(exchange.get_deposit(&accounts(4)), 500);
+    }
+
+    #[test]
+    fn test_reconcile_unreturned_swaps() {
+        let (mut context, mut contract) = contract_with_swap_sale();
+        let exchange_id = AccountId::new_unchecked("exchange.near".to_string());
+        // Output withdraw failed and the returned output bounced.
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(0)).build(),
+            PromiseResult::Failed,
+        );
+        let result = contract.on_swap_withdraw(accounts(1), U128(300), pending_swap(exchange_id.clone()));
+        assert!(matches!(result, PromiseOrValue::Value(U128(0))));
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(0)).build(),
+            PromiseResult::Failed,
+        );
+        assert!(!contract.after_return_swap_token(accounts(2), exchange_id.clone(), accounts(1), U128(50)));
+        let unreturned_swaps = contract.get_unreturned_swaps(accounts(2));
+        assert_eq!(unreturned_swaps.len(), 1);
+        assert_eq!(unreturned_swaps[0].exchange_amount.0, 300);
+        assert_eq!(unreturned_swaps[0].held_amount.0, 50);
+        assert_eq!(contract.get_unreturned_swap_accounts(0, 10).len(), 1);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.reconcile_unreturned_swaps(accounts(2));
+        assert!(contract.get_unreturned_swaps(accounts(2)).is_empty());
+
+        // Withdraw from the exchange failed again: the amount stays recorded.
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(0)).build(),
+            PromiseResult::Failed,
+        );
+        contract.on_reconcile_swap_withdraw(accounts(2), exchange_id, accounts(1), U128(300));
+        assert_eq!(contract.get_unreturned_swaps(accounts(2))[0].exchange_amount.0, 300);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_MUST_BE_OWNER")]
+    fn test_reconcile_unreturned_swaps_not_owner() {
+        let (mut context, mut contract) = contract_with_swap_sale();
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.reconcile_unreturned_swaps(accounts(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_TOKEN_NOT_ALLOWED")]
+    fn test_swap_deposit_wrong_token() {
+        let (mut context, mut contract) = contract_with_swap_sale();
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.ft_on_transfer(
+            accounts(2),
+            U128(500),
+            serde_json::to_string(&swap_sale_deposit(Some(SwapDeposit { min_amount_out: U128(100) }))).unwrap(),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_ENOUGH_GAS")]
+    fn test_swap_deposit_not_enough_gas() {
+        let (mut context, mut contract) = contract_with_swap_sale();
+        testing_env!(context.predecessor_account_id(accounts(4)).prepaid_gas(Gas(100_000_000_000_000)).build());
+        contract.ft_on_transfer(
+            accounts(2),
+            U128(500),
+            serde_json::to_string(&swap_sale_deposit(Some(SwapDeposit { min_amount_out: U128(100) }))).unwrap(),
+        );
+    }
+
+    fn contract_with_staking_swap_sale() -> (VMContextBuilder, Contract) {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), U128(1_000_000), vec![10, 20, 30]);
+        let mut input = sale_input(Some(10000), 0, 1_000_000_000);
+        input.limit_per_transaction = U128(1000);
+        input.swap = Some(SwapConfig {
+            exchange_id: AccountId::new_unchecked("exchange.near".to_string()),
+            pools: vec![SwapPool { token_id: accounts(4), pool_id: 7 }],
+        });
+        contract.create_sale(input);
+        register_account(&mut context, &mut contract, accounts(2));
+        (context, contract)
+    }
+
+    #[test]
+    fn test_swap_deposit_gas() {
+        let (_, contract) = contract_with_swap_sale();
+        let sale: Sale = contract.sales.get(&0).unwrap().into();
+        assert_eq!(sale.get_swap_deposit_gas(), Gas(130_000_000_000_000));
+    }
+
+    #[test]
+    fn test_staking_swap_deposit_gas() {
+        let (_, contract) = contract_with_staking_swap_sale();
+        let sale: Sale = contract.sales.get(&0).unwrap().into();
+        // Checking the stake adds 25 + 25 at each step of the chain.
+        assert_eq!(sale.get_swap_deposit_gas(), Gas(180_000_000_000_000));
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_ENOUGH_GAS")]
+    fn test_staking_swap_deposit_not_enough_gas() {
+        let (mut context, mut contract) = contract_with_staking_swap_sale();
+        testing_env!(context.predecessor_account_id(accounts(4)).prepaid_gas(Gas(170_000_000_000_000)).build());
+        let mut deposit = swap_sale_deposit(Some(SwapDeposit { min_amount_out: U128(100) }));
+        deposit.staking_contract = Some(AccountId::new_unchecked("test.staking".to_string()));
+        contract.ft_on_transfer(accounts(2), U128(500), serde_json::to_string(&deposit).unwrap());
+    }
+
+    fn contract_with_native_near_sale()-> (VMContextBuilder, Contract) {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), U128(1_000_000), vec![10, 20, 30]);
+        let mut input = sale_input(Some(10000), 0, 1_000_000_000);
+        input.staking_contracts = vec![];
+        input.min_near_deposit = U128(0);
+        input.deposit_token_id = AccountId::new_unchecked(NEAR_ACCOUNT.to_string());
+        input.native_near = Some(true);
+        contract.create_sale(input);
+        register_account(&mut context, &mut contract, accounts(2));
+        (context, contract)
+    }
+
+    #[test]
+    fn test_native_near_sale() {
+        let (mut context, mut contract) = contract_with_native_near_sale();
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(100).build());
+        contract.deposit_near(swap_sale_deposit(None));
+        assert_eq!(contract.get_sale(0).collected_amount.0, 100);
+        assert_eq!(contract.get_sale(0).near_balance.0, 100);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).attached_deposit(0).build());
+        contract.cancel_sale(0);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.claim_refund(0);
+        assert_eq!(contract.get_sale(0).near_balance.0, 0);
+
+        // Failed transfer returns NEAR to the escrow of the sale.
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(0)).build(),
+            PromiseResult::Failed,
+        );
+        let near_id = AccountId::new_unchecked(NEAR_ACCOUNT.to_string());
+        assert!(!contract.after_refund_purchase(accounts(2), U128(100), TokenAmount { token_id: near_id, amount: U128(100) }, 0));
+        assert_eq!(contract.get_sale(0).near_balance.0, 100);
+        assert_eq!(contract.get_sale_account(0, accounts(2)).refunded.0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_WRONG_TOKEN")]
+    fn test_native_near_sale_wrong_token() {
+        let (_, mut contract) = contract_with_sale();
+        let mut input = sale_input(Some(10000), 0, 1_000_000_000);
+        input.native_near = Some(true);
+        contract.create_sale(input);
+    }
+
+    fn contract_with_wrap_near_sale() -> (VMContextBuilder, Contract) {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), U128(1_000_000), vec![10, 20, 30]);
+        let mut input = sale_input(Some(10000), 0, 1_000_000_000);
+        input.staking_contracts = vec![];
+        input.min_near_deposit = U128(0);
+        input.deposit_token_id = AccountId::new_unchecked(WRAP_NEAR_ACCOUNT.to_string());
+        contract.create_sale(input);
+        register_account(&mut context, &mut contract, accounts(2));
+        (context, contract)
+    }
+
+    #[test]
+    fn test_near_deposit_wrapped() {
+        let (mut context, mut contract) = contract_with_wrap_near_sale();
+        testing_env!(context.predecessor_account_id(accounts(2)).attached_deposit(100).build());
+        let result = contract.deposit_near(swap_sale_deposit(None));
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        // Nothing is credited until the NEAR is wrapped.
+        assert_eq!(contract.get_sale(0).collected_amount.0, 0);
+
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(0)).attached_deposit(0).build(),
+            PromiseResult::Successful(vec![]),
+        );
+        contract.on_near_deposit_wrapped(accounts(2), U128(100), swap_sale_deposit(None));
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let unused = contract.near_sale_deposit(accounts(2), U128(100), swap_sale_deposit(None));
+        assert!(matches!(unused, PromiseOrValue::Value(U128(0))));
+        assert_eq!(contract.get_sale(0).collected_amount.0, 100);
+        assert!(contract.get_uncredited_near(accounts(2)).is_none());
+    }
+
+    /// Wrapped NEAR sale that checks the stake and the USD price of each deposit.
+    fn contract_with_wrap_near_usd_sale() -> (VMContextBuilder, Contract) {
+        let mut context = VMContextBuilder::new();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let mut contract = Contract::new(accounts(0), U128(1_000_000), vec![10, 20, 30]);
+        let mut input = sale_input(Some(10000), 0, 1_000_000_000);
+        input.deposit_token_id = AccountId::new_unchecked(WRAP_NEAR_ACCOUNT.to_string());
+        input.usd_oracle = Some(UsdOracleConfig {
+            oracle_id: AccountId::new_unchecked("oracle.near".to_string()),
+            max_price_age: U64(1_000),
+        });
+        contract.create_sale(input);
+        register_account(&mut context, &mut contract, accounts(2));
+        (context, contract)
+    }
+
+    #[test]
+    fn test_near_deposit_gas() {
+        let (mut context, mut contract) = contract_with_wrap_near_usd_sale();
+        let sale: Sale = contract.sales.get(&0).unwrap().into();
+        // 20 for the deposit, 25 + 25 for the stake and 10 + 40 for the USD price.
+        assert_eq!(sale.get_deposit_gas(), Gas(120_000_000_000_000));
+
+        let deposit = SaleDeposit {
+            sale_id: 0,
+            staking_contract: Some(AccountId::new_unchecked("test.staking".to_string())),
+            whitelist_proof: None,
+            limit_price: None,
+            swap: None,
+        };
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(100)
+            .prepaid_gas(Gas(200_000_000_000_000))
+            .build());
+        let result = contract.deposit_near(deposit);
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        drop(result);
+    }
+
+    #[test]
+    #[should_panic(expected = "ERR_NOT_ENOUGH_GAS")]
+    fn test_near_deposit_not_enough_gas() {
+        let (mut context, mut contract) = contract_with_wrap_near_usd_sale();
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(100)
+            .prepaid_gas(Gas(150_000_000_000_000))
+            .build());
+        contract.deposit_near(SaleDeposit {
+            sale_id: 0,
+            staking_contract: Some(AccountId::new_unchecked("test.staking".to_string())),
+            whitelist_proof: None,
+            limit_price: None,
+            swap: None,
+        });
+    }
+
+    #[test]
+    fn test_near_deposit_failed() {
+        let (mut context, mut contract) = contract_with_wrap_near_sale();
+        // Failed deposit returns the whole attached NEAR, unwrapping it first.
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        let result = contract.after_ft_on_transfer_near_deposit(Err(PromiseError::Failed), accounts(2), U128(100), 0);
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        assert_eq!(contract.get_sale(0).collected_amount.0, 0);
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(0)).build(),
+            PromiseResult::Failed,
+        );
+        contract.after_near_withdraw(accounts(2), U128(100));
+        assert_eq!(contract.get_uncredited_near(accounts(2)).unwrap().wrap_amount.0, 100);
+
+        // Wrap failed and the NEAR couldn't be sent back.
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(0)).build(),
+            PromiseResult::Failed,
+        );
+        contract.on_near_deposit_wrapped(accounts(2), U128(50), swap_sale_deposit(None));
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(0)).build(),
+            PromiseResult::Failed,
+        );
+        assert!(!contract.after_return_near(accounts(2), U128(50)));
+        let uncredited_near = contract.get_uncredited_near(accounts(2)).unwrap();
+        assert_eq!(uncredited_near.near_amount.0, 50);
+        assert_eq!(uncredited_near.wrap_amount.0, 100);
+        assert_eq!(contract.get_uncredited_near_accounts(0, 10).len(), 1);
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.reconcile_uncredited_near(accounts(2));
+        assert!(contract.get_uncredited_near(accounts(2)).is_none());
+        testing_env_with_promise_results(
+            context.predecessor_account_id(accounts(0)).build(),
+            PromiseResult::Failed,
         );
+        contract.after_reconcile_uncredited_near(accounts(2), U128(50), U128(0));
+        assert_eq!(contract.get_uncredited_near(accounts(2)).unwrap().near_amount.0, 50);
     }
 }
@@ -0,0 +1,280 @@
+use std::convert::TryInto;
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, LookupSet, Vector};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::log;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::Timestamp;
+
+use crate::*;
+use crate::sale::*;
+use crate::token_receiver::ext_staking_pool;
+
+const GAS_GET_ACCOUNT_STAKED_BALANCE: Gas = Gas(25_000_000_000_000);
+const GAS_ON_LOTTERY_REGISTRATION: Gas = Gas(10_000_000_000_000);
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LotteryConfig {
+    pub registration_start: U64,
+    /// Registration must end before the sale starts.
+    pub registration_end: U64,
+    pub num_winners: u64,
+    /// Amount of deposit token each winner buys for. Larger deposits are partially filled, smaller are rejected.
+    pub ticket_size: U128,
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct Lottery {
+    pub registration_start: Timestamp,
+    pub registration_end: Timestamp,
+    pub num_winners: u64,
+    pub ticket_size: Balance,
+    /// Accounts in the order of registration. Never changes after registration ends.
+    pub participants: Vector<AccountId>,
+    pub registered: LookupSet<AccountId>,
+    /// Positions of the participants swapped by the draw.
+    pub draw_positions: LookupMap<u64, AccountId>,
+    pub winners: LookupSet<AccountId>,
+    /// Random seed recorded at the start of the draw. Draw can be replayed from it.
+    pub seed: Option<Vec<u8>>,
+    pub num_drawn: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LotteryOutput {
+    pub registration_start: U64,
+    pub registration_end: U64,
+    pub num_winners: u64,
+    pub ticket_size: U128,
+    pub num_participants: u64,
+    pub num_drawn: u64,
+    pub seed: Option<Vec<u8>>,
+    pub is_drawn: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum LotteryStatus {
+    NotRegistered,
+    Registered,
+    Won,
+    Lost,
+}
+
+impl Lottery {
+    pub fn new(sale_id: u64, config: LotteryConfig) -> Self {
+        assert!(config.registration_start.0 < config.registration_end.0, "ERR_WRONG_REGISTRATION_DATES");
+        assert_ne!(config.num_winners, 0, "ERR_NO_WINNERS");
+        assert_ne!(config.ticket_size.0, 0, "ERR_NO_TICKET_SIZE");
+        Self {
+            registration_start: config.registration_start.0,
+            registration_end: config.registration_end.0,
+            num_winners: config.num_winners,
+            ticket_size: config.ticket_size.0,
+            participants: Vector::new(StorageKey::LotteryParticipants { sale_id }),
+            registered: LookupSet::new(StorageKey::LotteryRegistered { sale_id }),
+            draw_positions: LookupMap::new(StorageKey::LotteryDrawPositions { sale_id }),
+            winners: LookupSet::new(StorageKey::LotteryWinners { sale_id }),
+            seed: None,
+            num_drawn: 0,
+        }
+    }
+
+    fn get_num_to_draw(&self) -> u64 {
+        std::cmp::min(self.num_winners, self.participants.len())
+    }
+
+    pub(crate) fn is_drawn(&self) -> bool {
+        self.seed.is_some() && self.num_drawn == self.get_num_to_draw()
+    }
+
+    fn get_participant(&self, position: u64) -> AccountId {
+        self.draw_positions
+            .get(&position)
+            .unwrap_or_else(|| self.participants.get(position).unwrap())
+    }
+
+    /// Random number for the given step of the draw.
+    fn get_random(&self, step: u64) -> u128 {
+        let mut value = self.seed.clone().expect("ERR_NO_SEED");
+        value.extend_from_slice(&step.to_le_bytes());
+        u128::from_le_bytes(env::sha256(&value)[..16].try_into().unwrap())
+    }
+
+    /// Partial Fisher-Yates shuffle: step i picks a winner among positions i..n.
+    fn internal_draw(&mut self, limit: u64) {
+        let num_participants = self.participants.len();
+        let num_to_draw = std::cmp::min(self.num_drawn + limit, self.get_num_to_draw());
+        while self.num_drawn < num_to_draw {
+            let position = self.num_drawn;
+            let other_position = position + (self.get_random(position) % (num_participants - position) as u128) as u64;
+            let winner_id = self.get_participant(other_position);
+            let participant_id = self.get_participant(position);
+            self.draw_positions.insert(&other_position, &participant_id);
+            self.draw_positions.insert(&position, &winner_id);
+            self.winners.insert(&winner_id);
+            self.num_drawn += 1;
+        }
+    }
+
+    pub(crate) fn get_status(&self, account_id: &AccountId) -> LotteryStatus {
+        if !self.registered.contains(account_id) {
+            LotteryStatus::NotRegistered
+        } else if self.winners.contains(account_id) {
+            LotteryStatus::Won
+        } else if self.is_drawn() {
+            LotteryStatus::Lost
+        } else {
+            LotteryStatus::Registered
+        }
+    }
+}
+
+impl Sale {
+    fn lottery(&mut self) -> &mut Lottery {
+        self.lottery.as_mut().expect("ERR_NO_LOTTERY")
+    }
+
+    /// Returns the ticket size that the lottery winner can buy for.
+    pub(crate) fn assert_lottery_winner(&self, account_id: &AccountId) -> Balance {
+        let lottery = self.lottery.as_ref().expect("ERR_NO_LOTTERY");
+        assert!(lottery.is_drawn(), "ERR_LOTTERY_NOT_DRAWN");
+        assert!(lottery.winners.contains(account_id), "ERR_NOT_LOTTERY_WINNER");
+        lottery.ticket_size
+    }
+}
+
+impl Contract {
+    fn internal_register_for_lottery(&mut self, sale_id: u64, account_id: AccountId) {
+        let initial_storage_usage = env::storage_usage();
+        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        let lottery = sale.lottery();
+        assert!(!lottery.registered.contains(&account_id), "ERR_ALREADY_REGISTERED");
+        lottery.registered.insert(&account_id);
+        lottery.participants.push(&account_id);
+        log!("{} registered for sale #{}", account_id, sale_id);
+        self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+        self.internal_charge_storage(&account_id, initial_storage_usage);
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Registers predecessor for the lottery of the sale.
+    /// Staking contract is required if the sale requires staking.
+    pub fn register_for_lottery(&mut self, sale_id: u64, staking_contract: Option<AccountId>) -> PromiseOrValue<()> {
+        let account_id = env::predecessor_account_id();
+        assert!(self.accounts.get(&account_id).is_some(), "ERR_NOT_REGISTERED_ACCOUNT");
+        self.assert_storage_registered(&account_id);
+        let sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        let lottery = sale.lottery.as_ref().expect("ERR_NO_LOTTERY");
+        let timestamp = env::block_timestamp();
+        assert!(timestamp >= lottery.registration_start, "ERR_REGISTRATION_NOT_STARTED");
+        assert!(timestamp <= lottery.registration_end, "ERR_REGISTRATION_DONE");
+        assert!(!lottery.registered.contains(&account_id), "ERR_ALREADY_REGISTERED");
+
+        if !sale.staking_contracts.is_empty() {
+            let staking_contract = staking_contract.expect("ERR_MUST_HAVE_STAKING_CONTRACT");
+            assert!(
+                sale.staking_contracts.contains(&staking_contract),
+                "ERR_NOT_WHITELISTED_STAKING_CONTRACT"
+            );
+            PromiseOrValue::Promise(
+                ext_staking_pool::get_account_staked_balance(
+                    account_id.clone(),
+                    staking_contract,
+                    NO_DEPOSIT,
+                    GAS_GET_ACCOUNT_STAKED_BALANCE,
+                )
+                .then(ext_self::on_lottery_registration_staked_balance(
+                    sale_id,
+                    account_id,
+                    env::current_account_id(),
+                    NO_DEPOSIT,
+                    GAS_ON_LOTTERY_REGISTRATION,
+                )),
+            )
+        } else {
+            self.internal_register_for_lottery(sale_id, account_id);
+            PromiseOrValue::Value(())
+        }
+    }
+
+    #[private]
+    pub fn on_lottery_registration_staked_balance(
+        &mut self,
+        #[callback] staked_amount: U128,
+        sale_id: u64,
+        account_id: AccountId,
+    ) {
+        let sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        // Callback may land after the registration closed.
+        let lottery = sale.lottery.as_ref().expect("ERR_NO_LOTTERY");
+        assert!(env::block_timestamp() <= lottery.registration_end, "ERR_REGISTRATION_DONE");
+        log!("{} stake: {}", account_id, staked_amount.0);
+        assert!(staked_amount.0 >= sale.min_near_deposit, "ERR_NOT_ENOUGH_STAKED");
+        self.internal_register_for_lottery(sale_id, account_id);
+    }
+
+    /// Draws up to `limit` winners. Random seed is recorded on the first call.
+    /// Returns true when all the winners are drawn.
+    pub fn draw_lottery(&mut self, sale_id: u64, limit: u64) -> bool {
+        assert_eq!(
+            self.owner_id,
+            env::predecessor_account_id(),
+            "ERR_MUST_BE_OWNER"
+        );
+        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        let lottery = sale.lottery();
+        assert!(env::block_timestamp() > lottery.registration_end, "ERR_REGISTRATION_IN_PROGRESS");
+        if lottery.seed.is_none() {
+            lottery.seed = Some(env::random_seed());
+        }
+        lottery.internal_draw(limit);
+        let is_drawn = lottery.is_drawn();
+        log!("Sale #{} lottery drawn: {}/{}", sale_id, lottery.num_drawn, lottery.get_num_to_draw());
+        self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+        is_drawn
+    }
+
+    pub fn get_lottery(&self, sale_id: u64) -> LotteryOutput {
+        let sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        let lottery = sale.lottery.as_ref().expect("ERR_NO_LOTTERY");
+        LotteryOutput {
+            registration_start: U64(lottery.registration_start),
+            registration_end: U64(lottery.registration_end),
+            num_winners: lottery.num_winners,
+            ticket_size: U128(lottery.ticket_size),
+            num_participants: lottery.participants.len(),
+            num_drawn: lottery.num_drawn,
+            seed: lottery.seed.clone(),
+            is_drawn: lottery.is_drawn(),
+        }
+    }
+
+    /// Participants in the order of registration.
+    pub fn get_lottery_participants(&self, sale_id: u64, from_index: u64, limit: u64) -> Vec<AccountId> {
+        let sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        let lottery = sale.lottery.as_ref().expect("ERR_NO_LOTTERY");
+        (from_index..std::cmp::min(from_index + limit, lottery.participants.len()))
+            .map(|index| lottery.participants.get(index).unwrap())
+            .collect()
+    }
+
+    /// Winners in the order of the draw.
+    pub fn get_lottery_winners(&self, sale_id: u64, from_index: u64, limit: u64) -> Vec<AccountId> {
+        let sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        let lottery = sale.lottery.as_ref().expect("ERR_NO_LOTTERY");
+        (from_index..std::cmp::min(from_index + limit, lottery.num_drawn))
+            .map(|position| lottery.get_participant(position))
+            .collect()
+    }
+
+    pub fn get_lottery_status(&self, sale_id: u64, account_id: AccountId) -> LotteryStatus {
+        let sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        sale.lottery.as_ref().expect("ERR_NO_LOTTERY").get_status(&account_id)
+    }
+}
@@ -32,6 +32,10 @@ impl Contract {
             links: old_contract.links,
             num_sales: old_contract.num_sales,
             accounts_old: old_contract.accounts,
+            storage_accounts: LookupMap::new(StorageKey::StorageAccounts),
+            account_sale_ids: LookupMap::new(StorageKey::AccountsSaleIds),
+            uncredited_near: UnorderedMap::new(StorageKey::UncreditedNear),
+            unreturned_swaps: UnorderedMap::new(StorageKey::UnreturnedSwaps),
         }
     }
 
@@ -0,0 +1,53 @@
+use near_sdk::log;
+
+use crate::*;
+use crate::sale::*;
+
+#[near_bindgen]
+impl Contract {
+    // add storage management
+    #[private]
+    #[init(ignore_state)]
+    #[allow(dead_code)]
+    pub fn migrate_storage() -> Self {
+        #[derive(BorshDeserialize)]
+        struct OldContract {
+            owner_id: AccountId,
+            join_fee: Balance,
+            referral_fees: Vec<u64>,
+            accounts: UnorderedMap<AccountId, VAccount>,
+            sales: LookupMap<u64, VSale>,
+            links: LookupMap<PublicKey, AccountId>,
+            num_sales: u64,
+            accounts_old: UnorderedMap<AccountId, AccountOld>,
+        }
+
+        let old_contract: OldContract = env::state_read().expect("Old state doesn't exist");
+
+        Self {
+            owner_id: old_contract.owner_id,
+            join_fee: old_contract.join_fee,
+            referral_fees: old_contract.referral_fees,
+            accounts: old_contract.accounts,
+            sales: old_contract.sales,
+            links: old_contract.links,
+            num_sales: old_contract.num_sales,
+            accounts_old: old_contract.accounts_old,
+            storage_accounts: LookupMap::new(StorageKey::StorageAccounts),
+            account_sale_ids: LookupMap::new(StorageKey::AccountsSaleIds),
+            uncredited_near: UnorderedMap::new(StorageKey::UncreditedNear),
+            unreturned_swaps: UnorderedMap::new(StorageKey::UnreturnedSwaps),
+        }
+    }
+
+    // fill portfolio index with the existing deposits
+    #[private]
+    pub fn migrate_portfolio(&mut self, sale_id: u64, from_index: u64, limit: u64) {
+        let sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        let keys = sale.account_sales.keys_as_vector();
+        for index in from_index..std::cmp::min(from_index + limit, keys.len()) {
+            self.internal_add_to_portfolio(&keys.get(index).unwrap(), sale_id);
+        }
+        log!("Pending items: {}", keys.len().saturating_sub(from_index + limit));
+    }
+}
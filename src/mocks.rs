@@ -0,0 +1,145 @@
+//! In-process stand-ins of the external contracts for the unit tests.
+//! Each mock keeps its own state and returns what the real contract would return to the callback.
+
+use std::collections::HashMap;
+
+use near_sdk::json_types::{U128, U64};
+use near_sdk::{serde_json, AccountId, Balance, PromiseError, PromiseResult};
+
+use crate::price_oracle::OraclePrice;
+use crate::swap::SwapAction;
+
+struct MockPool {
+    token_ids: [AccountId; 2],
+    amounts: [Balance; 2],
+}
+
+/// Ref-style exchange with constant product pools and no fees.
+pub struct MockExchange {
+    pools: HashMap<u64, MockPool>,
+    /// Token balances of the fundraiser inside of the exchange.
+    deposits: HashMap<AccountId, Balance>,
+    /// Makes `withdraw` fail, like a transfer to the account not registered with the token.
+    pub withdraw_fails: bool,
+}
+
+impl MockExchange {
+    pub fn new() -> Self {
+        Self {
+            pools: HashMap::new(),
+            deposits: HashMap::new(),
+            withdraw_fails: false,
+        }
+    }
+
+    pub fn add_pool(&mut self, pool_id: u64, token_a: AccountId, amount_a: Balance, token_b: AccountId, amount_b: Balance) {
+        self.pools.insert(pool_id, MockPool { token_ids: [token_a, token_b], amounts: [amount_a, amount_b] });
+    }
+
+    pub fn get_deposit(&self, token_id: &AccountId) -> Balance {
+        self.deposits.get(token_id).copied().unwrap_or(0)
+    }
+
+    /// `ft_on_transfer` of the exchange. Returns the used amount, as `ft_transfer_call` does.
+    pub fn deposit(&mut self, token_id: &AccountId, amount: U128) -> U128 {
+        *self.deposits.entry(token_id.clone()).or_insert(0) += amount.0;
+        amount
+    }
+
+    /// `swap` of the exchange. Fails without changes if any action is under its min_amount_out.
+    pub fn swap(&mut self, actions: Vec<SwapAction>) -> Result<U128, PromiseError> {
+        let mut amount_out = 0;
+        let mut updates = vec![];
+        for action in actions {
+            let amount_in = action.amount_in.map(|amount_in| amount_in.0).unwrap_or(amount_out);
+            let pool = self.pools.get(&action.pool_id).ok_or(PromiseError::Failed)?;
+            let index_in = pool.token_ids.iter().position(|token_id| token_id == &action.token_in).ok_or(PromiseError::Failed)?;
+            let index_out = 1 - index_in;
+            if pool.token_ids[index_out] != action.token_out || self.get_deposit(&action.token_in) < amount_in {
+                return Err(PromiseError::Failed);
+            }
+            amount_out = amount_in * pool.amounts[index_out] / (pool.amounts[index_in] + amount_in);
+            if amount_out < action.min_amount_out.0 {
+                return Err(PromiseError::Failed);
+            }
+            updates.push((action.pool_id, index_in, amount_in, amount_out, action.token_in, action.token_out));
+        }
+        for (pool_id, index_in, amount_in, amount_out, token_in, token_out) in updates {
+            let pool = self.pools.get_mut(&pool_id).unwrap();
+            pool.amounts[index_in] += amount_in;
+            pool.amounts[1 - index_in] -= amount_out;
+            *self.deposits.get_mut(&token_in).unwrap() -= amount_in;
+            *self.deposits.entry(token_out).or_insert(0) += amount_out;
+        }
+        Ok(U128(amount_out))
+    }
+
+    /// `withdraw` of the exchange, as the promise result seen by the callback.
+    pub fn withdraw(&mut self, token_id: &AccountId, amount: U128) -> PromiseResult {
+        if self.withdraw_fails || self.get_deposit(token_id) < amount.0 {
+            return PromiseResult::Failed;
+        }
+        *self.deposits.get_mut(token_id).unwrap() -= amount.0;
+        PromiseResult::Successful(vec![])
+    }
+}
+
+/// Price oracle returning the prices set by the test.
+pub struct MockPriceOracle {
+    prices: HashMap<AccountId, (Balance, u8, u64)>,
+}
+
+impl MockPriceOracle {
+    pub fn new() -> Self {
+        Self { prices: HashMap::new() }
+    }
+
+    /// A unit of the token is worth multiplier / 10^decimals USD units since the timestamp.
+    pub fn set_price(&mut self, token_id: AccountId, multiplier: Balance, decimals: u8, timestamp: u64) {
+        self.prices.insert(token_id, (multiplier, decimals, timestamp));
+    }
+
+    /// `get_price` of the oracle, as the promise result seen by the callback.
+    pub fn get_price_result(&self, token_id: &AccountId) -> PromiseResult {
+        PromiseResult::Successful(serde_json::to_vec(&self.get_price(token_id)).unwrap())
+    }
+
+    /// `get_price` of the oracle.
+    pub fn get_price(&self, token_id: &AccountId) -> OraclePrice {
+        let (multiplier, decimals, timestamp) = *self.prices.get(token_id).expect("ERR_NO_PRICE");
+        OraclePrice {
+            multiplier: U128(multiplier),
+            decimals,
+            timestamp: U64(timestamp),
+        }
+    }
+}
+
+/// Staking pool with the staked balances set by the test.
+pub struct MockStakingPool {
+    balances: HashMap<AccountId, Balance>,
+}
+
+impl MockStakingPool {
+    pub fn new() -> Self {
+        Self { balances: HashMap::new() }
+    }
+
+    pub fn stake(&mut self, account_id: AccountId, amount: Balance) {
+        *self.balances.entry(account_id).or_insert(0) += amount;
+    }
+
+    /// `get_account_staked_balance` of the pool, as the promise result seen by the callback.
+    pub fn get_account_staked_balance_result(&self, account_id: &AccountId) -> PromiseResult {
+        let balance = U128(self.balances.get(account_id).copied().unwrap_or(0));
+        PromiseResult::Successful(serde_json::to_vec(&balance).unwrap())
+    }
+}
+
+/// Decodes the JSON value of the successful promise result, as `#[callback]` does.
+pub fn promise_result_value<T: near_sdk::serde::de::DeserializeOwned>(result: &PromiseResult) -> T {
+    match result {
+        PromiseResult::Successful(value) => serde_json::from_slice(value).unwrap(),
+        _ => panic!("ERR_PROMISE_FAILED"),
+    }
+}
@@ -0,0 +1,56 @@
+use near_contract_standards::fungible_token::core_impl::ext_fungible_token;
+use near_sdk::log;
+
+use crate::*;
+use crate::sale::*;
+
+/// Deposits of the sales in native NEAR are recorded under this token id.
+pub(crate) fn is_native_near_token(token_id: &AccountId) -> bool {
+    token_id.as_str() == NEAR_ACCOUNT
+}
+
+impl Sale {
+    /// Returns NEAR of the failed transfer to the escrow of the sale.
+    pub(crate) fn internal_rollback_near_payout(&mut self, token_id: &AccountId, amount: Balance) {
+        if is_native_near_token(token_id) {
+            self.near_balance += amount;
+        }
+    }
+}
+
+impl Contract {
+    /// Adds the accepted NEAR deposit to the escrow of the sale.
+    pub(crate) fn internal_record_near_deposit(&mut self, sale_id: u64, amount: Balance) {
+        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        sale.near_balance += amount;
+        self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+    }
+
+    /// Sends the deposit token of the sale: native NEAR out of the escrow of the sale, other tokens with `ft_transfer`.
+    /// Sale must be saved before the call.
+    pub(crate) fn internal_send_deposit_token(
+        &mut self,
+        sale_id: u64,
+        receiver_id: AccountId,
+        token_id: AccountId,
+        amount: Balance,
+        memo: String,
+    ) -> Promise {
+        if is_native_near_token(&token_id) {
+            let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+            sale.near_balance = sale.near_balance.checked_sub(amount).expect("ERR_NOT_ENOUGH_NEAR");
+            self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+            log!("{}", memo);
+            Promise::new(receiver_id).transfer(amount)
+        } else {
+            ext_fungible_token::ft_transfer(
+                receiver_id,
+                amount.into(),
+                Some(memo),
+                token_id,
+                ONE_YOCTO,
+                GAS_FOR_FT_TRANSFER,
+            )
+        }
+    }
+}
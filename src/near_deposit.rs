@@ -0,0 +1,232 @@
+use near_contract_standards::fungible_token::core_impl::ext_fungible_token;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::log;
+
+use crate::*;
+use crate::sale::*;
+use crate::token_receiver::SaleDeposit;
+
+const GAS_NEAR_WITHDRAW: Gas = Gas(10_000_000_000_000);
+const GAS_AFTER_RETURN_NEAR: Gas = Gas(10_000_000_000_000);
+const GAS_AFTER_NEAR_WITHDRAW: Gas = Gas(20_000_000_000_000);
+/// Execution of `deposit_near` and `on_near_deposit_wrapped` besides the calls they schedule.
+const GAS_FOR_NEAR_DEPOSIT_EXECUTION: Gas = Gas(10_000_000_000_000);
+const GAS_AFTER_RECONCILE_UNCREDITED_NEAR: Gas = Gas(10_000_000_000_000);
+
+/// NEAR the contract received from the account but could neither credit to a sale nor send back.
+/// `wrap_amount` is the part that is still held as wrap.near.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct UncreditedNear {
+    pub near_amount: U128,
+    pub wrap_amount: U128,
+}
+
+impl Sale {
+    /// Gas of `on_near_deposit_wrapped`: the deposit into the sale and the callback after it.
+    fn get_on_near_deposit_wrapped_gas(&self) -> Gas {
+        Gas(self.get_deposit_gas().0 + GAS_AFTER_FT_ON_TRANSFER_NEAR_DEPOSIT.0 + GAS_FOR_NEAR_DEPOSIT_EXECUTION.0)
+    }
+}
+
+impl Contract {
+    /// Wraps the attached NEAR first, the sale is credited only after wrap.near minted the tokens.
+    /// Without enough gas for the whole chain the deposit would only be wrapped and sent back.
+    pub(crate) fn internal_wrap_near_deposit(
+        &mut self,
+        sale: &Sale,
+        sender_id: AccountId,
+        amount: Balance,
+        sale_deposit: SaleDeposit,
+    ) -> Promise {
+        let on_wrapped_gas = sale.get_on_near_deposit_wrapped_gas();
+        assert!(
+            env::prepaid_gas() >= GAS_NEAR_DEPOSIT + on_wrapped_gas + GAS_FOR_NEAR_DEPOSIT_EXECUTION,
+            "ERR_NOT_ENOUGH_GAS"
+        );
+        ext_wrap_near::near_deposit(
+            AccountId::new_unchecked(WRAP_NEAR_ACCOUNT.to_string()),
+            amount,
+            GAS_NEAR_DEPOSIT,
+        )
+        .then(ext_self::on_near_deposit_wrapped(
+            sender_id,
+            U128(amount),
+            sale_deposit,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            on_wrapped_gas,
+        ))
+    }
+
+    /// Sends NEAR back to the account, recording it as uncredited if the transfer fails.
+    pub(crate) fn internal_return_near(&mut self, account_id: AccountId, amount: Balance) -> Promise {
+        Promise::new(account_id.clone())
+            .transfer(amount)
+            .then(ext_self::after_return_near(
+                account_id,
+                U128(amount),
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_AFTER_RETURN_NEAR,
+            ))
+    }
+
+    /// Unwraps the part of the wrapped deposit that the sale didn't accept and sends it back.
+    pub(crate) fn internal_unwrap_and_return_near(&mut self, account_id: AccountId, amount: Balance) -> Promise {
+        ext_wrap_near::near_withdraw(
+            U128(amount),
+            AccountId::new_unchecked(WRAP_NEAR_ACCOUNT.to_string()),
+            ONE_YOCTO,
+            GAS_NEAR_WITHDRAW,
+        )
+        .then(ext_self::after_near_withdraw(
+            account_id,
+            U128(amount),
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_AFTER_NEAR_WITHDRAW,
+        ))
+    }
+
+    pub(crate) fn internal_record_uncredited_near(&mut self, account_id: &AccountId, near_amount: Balance, wrap_amount: Balance) {
+        let mut uncredited_near = self.uncredited_near.get(account_id).unwrap_or(UncreditedNear {
+            near_amount: U128(0),
+            wrap_amount: U128(0),
+        });
+        uncredited_near.near_amount = U128(uncredited_near.near_amount.0 + near_amount);
+        uncredited_near.wrap_amount = U128(uncredited_near.wrap_amount.0 + wrap_amount);
+        self.uncredited_near.insert(account_id, &uncredited_near);
+        log!("Uncredited NEAR of {}: {}, wrapped: {}", account_id, near_amount, wrap_amount);
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Credits the deposit once the NEAR is wrapped, otherwise returns it.
+    #[private]
+    pub fn on_near_deposit_wrapped(
+        &mut self,
+        sender_id: AccountId,
+        deposit_amount: U128,
+        sale_deposit: SaleDeposit,
+    ) -> Promise {
+        if !is_promise_success() {
+            log!("Wrap of the deposit of {} failed. NEAR to return: {}", sender_id, deposit_amount.0);
+            return self.internal_return_near(sender_id, deposit_amount.0);
+        }
+        let sale_id = sale_deposit.sale_id;
+        let sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        ext_self::near_sale_deposit(
+            sender_id.clone(),
+            deposit_amount,
+            sale_deposit,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            sale.get_deposit_gas(),
+        )
+        .then(ext_self::after_ft_on_transfer_near_deposit(
+            sender_id,
+            deposit_amount,
+            sale_id,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_AFTER_FT_ON_TRANSFER_NEAR_DEPOSIT,
+        ))
+    }
+
+    /// Deposits the wrapped NEAR like a transfer of wrap.near from the sender.
+    /// Separate call so that a failed deposit can still return the NEAR.
+    #[private]
+    pub fn near_sale_deposit(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        sale_deposit: SaleDeposit,
+    ) -> PromiseOrValue<U128> {
+        let token_id = AccountId::new_unchecked(WRAP_NEAR_ACCOUNT.to_string());
+        self.internal_ft_on_transfer(token_id, sender_id, amount, sale_deposit)
+    }
+
+    #[private]
+    pub fn after_near_withdraw(&mut self, account_id: AccountId, amount: U128) -> PromiseOrValue<U128> {
+        if is_promise_success() {
+            self.internal_return_near(account_id, amount.0).into()
+        } else {
+            self.internal_record_uncredited_near(&account_id, 0, amount.0);
+            PromiseOrValue::Value(U128(0))
+        }
+    }
+
+    #[private]
+    pub fn after_return_near(&mut self, account_id: AccountId, amount: U128) -> bool {
+        let promise_success = is_promise_success();
+        if !promise_success {
+            self.internal_record_uncredited_near(&account_id, amount.0, 0);
+        }
+        promise_success
+    }
+
+    /// Sends the uncredited NEAR and wrap.near back to the account and clears the record.
+    pub fn reconcile_uncredited_near(&mut self, account_id: AccountId) {
+        assert_eq!(
+            self.owner_id,
+            env::predecessor_account_id(),
+            "ERR_MUST_BE_OWNER"
+        );
+        let uncredited_near = self.uncredited_near.remove(&account_id).expect("ERR_NO_DATA");
+        if uncredited_near.near_amount.0 > 0 {
+            Promise::new(account_id.clone())
+                .transfer(uncredited_near.near_amount.0)
+                .then(ext_self::after_reconcile_uncredited_near(
+                    account_id.clone(),
+                    uncredited_near.near_amount,
+                    U128(0),
+                    env::current_account_id(),
+                    NO_DEPOSIT,
+                    GAS_AFTER_RECONCILE_UNCREDITED_NEAR,
+                ));
+        }
+        if uncredited_near.wrap_amount.0 > 0 {
+            ext_fungible_token::ft_transfer(
+                account_id.clone(),
+                uncredited_near.wrap_amount,
+                Some("Uncredited NEAR deposit".to_string()),
+                AccountId::new_unchecked(WRAP_NEAR_ACCOUNT.to_string()),
+                ONE_YOCTO,
+                GAS_FOR_FT_TRANSFER,
+            )
+            .then(ext_self::after_reconcile_uncredited_near(
+                account_id,
+                U128(0),
+                uncredited_near.wrap_amount,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_AFTER_RECONCILE_UNCREDITED_NEAR,
+            ));
+        }
+    }
+
+    #[private]
+    pub fn after_reconcile_uncredited_near(&mut self, account_id: AccountId, near_amount: U128, wrap_amount: U128) -> bool {
+        let promise_success = is_promise_success();
+        if !promise_success {
+            self.internal_record_uncredited_near(&account_id, near_amount.0, wrap_amount.0);
+        }
+        promise_success
+    }
+
+    pub fn get_uncredited_near(&self, account_id: AccountId) -> Option<UncreditedNear> {
+        self.uncredited_near.get(&account_id)
+    }
+
+    pub fn get_uncredited_near_accounts(&self, from_index: u64, limit: u64) -> Vec<(AccountId, UncreditedNear)> {
+        let keys = self.uncredited_near.keys_as_vector();
+        let values = self.uncredited_near.values_as_vector();
+        (from_index..std::cmp::min(from_index + limit, keys.len()))
+            .map(|index| (keys.get(index).unwrap(), values.get(index).unwrap()))
+            .collect()
+    }
+}
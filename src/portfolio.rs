@@ -0,0 +1,64 @@
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+
+use crate::*;
+use crate::sale::*;
+
+/// Position of the account in a single sale.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PortfolioItem {
+    pub sale_id: u64,
+    pub deposited: U128,
+    /// Distribute tokens purchased. Estimated at the current state while the sale is in progress.
+    /// Zero for the legacy sales without price or decimals of the distribute token.
+    pub allocated: U128,
+    pub claimable: U128,
+    pub claimed: U128,
+    pub refundable: U128,
+    pub affiliate_reward: U128,
+}
+
+impl Contract {
+    pub(crate) fn internal_add_to_portfolio(&mut self, account_id: &AccountId, sale_id: u64) {
+        let mut sale_ids = self.account_sale_ids.get(account_id).unwrap_or_else(|| {
+            UnorderedSet::new(StorageKey::AccountSaleIds { account_id: account_id.clone() })
+        });
+        if sale_ids.insert(&sale_id) {
+            self.account_sale_ids.insert(account_id, &sale_ids);
+        }
+    }
+
+    fn get_portfolio_item(&self, sale_id: u64, account_id: &AccountId) -> Option<PortfolioItem> {
+        let sale: Sale = self.sales.get(&sale_id)?.into();
+        let preview = self.internal_preview_settlement(&sale, account_id);
+        Some(PortfolioItem {
+            sale_id,
+            deposited: preview.amount,
+            allocated: preview.amount_to_claim,
+            claimable: preview.claimable,
+            claimed: preview.claimed,
+            refundable: preview.refundable,
+            affiliate_reward: if preview.affiliate_reward_claimed { U128(0) } else { preview.affiliate_reward },
+        })
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sales the account deposited into, with its position in each.
+    pub fn get_account_portfolio(&self, account_id: AccountId, from_index: u64, limit: u64) -> Vec<PortfolioItem> {
+        if let Some(sale_ids) = self.account_sale_ids.get(&account_id) {
+            let sale_ids = sale_ids.as_vector();
+            (from_index..std::cmp::min(from_index + limit, sale_ids.len()))
+                .filter_map(|index| self.get_portfolio_item(sale_ids.get(index).unwrap(), &account_id))
+                .collect()
+        } else {
+            vec![]
+        }
+    }
+
+    pub fn get_account_num_sales(&self, account_id: AccountId) -> u64 {
+        self.account_sale_ids.get(&account_id).map(|sale_ids| sale_ids.len()).unwrap_or(0)
+    }
+}
@@ -0,0 +1,100 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{ext_contract, log};
+
+use crate::*;
+use crate::deposit_tokens::RATE_DENOMINATOR;
+use crate::sale::*;
+
+pub(crate) const GAS_GET_USD_PRICE: Gas = Gas(10_000_000_000_000);
+pub(crate) const GAS_ON_GET_USD_PRICE: Gas = Gas(40_000_000_000_000);
+
+#[ext_contract(ext_price_oracle)]
+pub trait ExtPriceOracle {
+    /// USD price of the token.
+    fn get_price(&self, token_id: AccountId) -> OraclePrice;
+}
+
+/// USD value of a single unit of the token is multiplier / 10^decimals, in the units of the sale.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OraclePrice {
+    pub multiplier: U128,
+    pub decimals: u8,
+    /// Time the price was last updated.
+    pub timestamp: U64,
+}
+
+/// Sale with `price` and caps in USD. Every deposit is valued by the oracle at the deposit time.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct UsdOracleConfig {
+    pub oracle_id: AccountId,
+    /// Prices older than this are rejected, in nanoseconds.
+    pub max_price_age: U64,
+}
+
+impl OraclePrice {
+    /// Rate of the token in the units of deposit_tokens.
+    fn get_rate(&self) -> Balance {
+        (U256::from(self.multiplier.0) * U256::from(RATE_DENOMINATOR)
+            / U256::from(10).pow(U256::from(self.decimals))).as_u128()
+    }
+}
+
+impl Contract {
+    /// Records the deposit, first getting the USD price of the token if the sale is priced in USD.
+    pub(crate) fn internal_deposit_with_price(
+        &mut self,
+        sale: &Sale,
+        deposit: PendingDeposit,
+        staked_amount: Balance,
+    ) -> PromiseOrValue<U128> {
+        if let Some(usd_oracle) = sale.usd_oracle.as_ref() {
+            PromiseOrValue::Promise(
+                ext_price_oracle::get_price(
+                    deposit.token_id.clone(),
+                    usd_oracle.oracle_id.clone(),
+                    NO_DEPOSIT,
+                    GAS_GET_USD_PRICE,
+                )
+                .then(ext_self::on_get_usd_price(
+                    deposit,
+                    U128(staked_amount),
+                    env::current_account_id(),
+                    NO_DEPOSIT,
+                    GAS_ON_GET_USD_PRICE,
+                )),
+            )
+        } else {
+            PromiseOrValue::Value(U128(self.internal_sale_deposit(&deposit, staked_amount)))
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    #[private]
+    pub fn on_get_usd_price(
+        &mut self,
+        #[callback] price: OraclePrice,
+        deposit: PendingDeposit,
+        staked_amount: U128,
+    ) -> U128 {
+        let sale_id = deposit.sale_id;
+        let token_id = &deposit.token_id;
+        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        let usd_oracle = sale.usd_oracle.clone().expect("ERR_NO_USD_ORACLE");
+        assert!(
+            env::block_timestamp() <= price.timestamp.0 + usd_oracle.max_price_age.0,
+            "ERR_STALE_PRICE"
+        );
+        let rate = price.get_rate();
+        assert_ne!(rate, 0, "ERR_WRONG_PRICE");
+        log!("{} USD rate: {}", token_id, rate);
+        sale.internal_set_deposit_token_rate(token_id, rate);
+        self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+        U128(self.internal_sale_deposit(&deposit, staked_amount.0))
+    }
+}
@@ -0,0 +1,68 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+
+use crate::*;
+use crate::sale::*;
+
+/// Next `amount` of distribute tokens are sold at `price`.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PriceTier {
+    pub amount: U128,
+    /// Price per a single token in decimals of the deposit token.
+    pub price: U128,
+}
+
+pub(crate) fn assert_valid_price_tiers(price_tiers: &[PriceTier]) {
+    assert!(!price_tiers.is_empty(), "ERR_NO_PRICE_TIERS");
+    for tier in price_tiers {
+        assert_ne!(tier.amount.0, 0, "ERR_WRONG_PRICE_TIER");
+        assert_ne!(tier.price.0, 0, "ERR_WRONG_PRICE_TIER");
+    }
+}
+
+impl Sale {
+    /// Index of the tier that the next deposit is filled at. None if all tiers are sold out.
+    pub(crate) fn get_current_tier(&self) -> Option<usize> {
+        let mut tier_end = 0;
+        for (index, tier) in self.price_tiers.as_ref()?.iter().enumerate() {
+            tier_end += tier.amount.0;
+            if self.sold_amount < tier_end {
+                return Some(index);
+            }
+        }
+        None
+    }
+
+    /// Fills the deposit tier by tier, starting from the tokens already sold.
+    /// Returns part of the deposit that was used and amount of distribute tokens bought.
+    pub(crate) fn internal_tiered_fill(&mut self, amount: Balance) -> (Balance, Balance) {
+        let decimals = U256::from(u128::pow(10, self.distribute_token_decimals.expect("ERR_NO_TOKEN_DECIMALS") as u32));
+        let price_tiers = self.price_tiers.clone().expect("ERR_NO_PRICE_TIERS");
+
+        let mut remaining = amount;
+        let mut tokens = 0;
+        let mut tier_end = 0;
+        for tier in price_tiers {
+            tier_end += tier.amount.0;
+            if self.sold_amount >= tier_end {
+                continue;
+            }
+            let available = tier_end - self.sold_amount;
+            let available_cost = ((U256::from(available) * U256::from(tier.price.0) + decimals - 1) / decimals).as_u128();
+            if remaining >= available_cost {
+                tokens += available;
+                self.sold_amount += available;
+                remaining -= available_cost;
+            } else {
+                let bought = (U256::from(remaining) * decimals / U256::from(tier.price.0)).as_u128();
+                tokens += bought;
+                self.sold_amount += bought;
+                remaining -= ((U256::from(bought) * U256::from(tier.price.0) + decimals - 1) / decimals).as_u128();
+                break;
+            }
+        }
+        (amount - remaining, tokens)
+    }
+}
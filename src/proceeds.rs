@@ -0,0 +1,160 @@
+use near_sdk::json_types::U128;
+use near_sdk::log;
+
+use crate::*;
+use crate::events::emit_sale_update;
+use crate::sale::*;
+
+impl Sale {
+    /// Part of collected_amount that is owed back to participants.
+    pub(crate) fn get_total_refund(&self) -> Balance {
+        if let Some(result) = &self.result {
+            return result.total_refund.0;
+        }
+        if self.is_full_refund() {
+            return self.collected_amount;
+        }
+        match self.sale_type {
+            SaleType::ByAmount | SaleType::Lottery => 0,
+            SaleType::DutchAuction => {
+                if self.is_dutch_auction_done(env::block_timestamp()) {
+                    // Purchase value is rounded up per account, so the refunds never exceed this.
+                    self.collected_amount.saturating_sub(self.get_dutch_auction_purchase_value(self.sold_amount))
+                } else {
+                    self.collected_amount
+                }
+            }
+            SaleType::BatchAuction => {
+                if let Some(clearing_price) = self.clearing_price {
+                    self.collected_amount.saturating_sub(get_purchase_value(self, self.sold_amount, clearing_price))
+                } else {
+                    self.collected_amount
+                }
+            }
+            SaleType::BySubscription => self.get_subscription_refund(),
+        }
+    }
+
+    /// Upper bound of the refunds of the subscription sale, rounded down per account in `get_amount_to_claim`:
+    /// tokens of the deposit, then its share of the supply, then the value of that share.
+    /// Each of the three divisions loses less than one unit per account, so with `n` accounts
+    /// the purchases add up to at least `((F - n) * S / F - n) * price / 10^decimals - n`,
+    /// where `F` is all the collected tokens and `S` is the supply (`F` if not oversubscribed).
+    fn get_subscription_refund(&self) -> Balance {
+        let distribute_token_decimals = match self.distribute_token_decimals {
+            Some(distribute_token_decimals) if self.price > 0 => distribute_token_decimals,
+            _ => return self.collected_amount.saturating_sub(self.max_amount),
+        };
+        let decimals = U256::from(u128::pow(10, distribute_token_decimals as u32));
+        let num_accounts = U256::from(self.account_sales.len());
+        let filled_tokens = decimals * U256::from(self.collected_amount) / U256::from(self.price);
+        if filled_tokens.is_zero() {
+            return self.collected_amount;
+        }
+        let supply_tokens = if self.max_amount >= self.collected_amount {
+            filled_tokens
+        } else {
+            U256::from(self.distribute_supply_amount.expect("ERR_MUST_HAVE_SUPPLY_AMOUNT"))
+        };
+        let min_tokens = (filled_tokens.saturating_sub(num_accounts) * supply_tokens / filled_tokens)
+            .saturating_sub(num_accounts);
+        let min_purchase = (min_tokens * U256::from(self.price) / decimals).saturating_sub(num_accounts);
+        self.collected_amount - std::cmp::min(min_purchase.as_u128(), self.collected_amount)
+    }
+
+    /// Part of collected_amount that is owed back to participants or affiliates.
+    pub(crate) fn get_reserved_amount(&self) -> Balance {
+        if let Some(result) = &self.result {
+            return result.total_refund.0 + result.total_affiliate_rewards.0;
+        }
+        if self.is_affiliate_reward_available() {
+            self.get_total_refund() + self.total_affiliate_rewards
+        } else {
+            self.get_total_refund()
+        }
+    }
+
+    /// Proceeds in deposit_token_id that can be withdrawn to the beneficiary now.
+    /// Other deposit tokens keep the same share of their deposits.
+    pub(crate) fn get_available_proceeds(&self) -> Balance {
+        let collected_amount = self.get_collected_amount();
+        let kept_amount = collected_amount.saturating_sub(self.get_reserved_amount());
+        let primary_collected_amount = self.get_primary_collected_amount();
+        let primary_kept_amount = if primary_collected_amount == collected_amount {
+            kept_amount
+        } else {
+            (U256::from(primary_collected_amount) * U256::from(kept_amount) / U256::from(collected_amount)).as_u128()
+        };
+        (primary_kept_amount + self.penalty_amount).saturating_sub(self.withdrawn_amount)
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sends collected proceeds that are not owed back to the sale beneficiary.
+    /// `token_id` is one of the deposit tokens of the sale, deposit_token_id by default.
+    pub fn withdraw_proceeds(&mut self, sale_id: u64, token_id: Option<AccountId>) -> Promise {
+        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        let beneficiary_id = sale.beneficiary_id.clone().expect("ERR_NO_BENEFICIARY");
+        let predecessor_id = env::predecessor_account_id();
+        assert!(
+            predecessor_id == self.owner_id || predecessor_id == beneficiary_id,
+            "ERR_NOT_ALLOWED"
+        );
+        // Soft cap sales may still fail until the end date.
+        if sale.sale_type != SaleType::ByAmount || sale.soft_cap.is_some() {
+            assert!(env::block_timestamp() > sale.end_date, "ERR_SALE_IN_PROGRESS");
+        }
+
+        let token_account_id = token_id.unwrap_or_else(|| sale.deposit_token_id.clone());
+        let amount = sale.get_available_token_proceeds(&token_account_id);
+        assert_ne!(amount, 0, "ERR_NOTHING_TO_WITHDRAW");
+        sale.internal_record_token_withdraw(&token_account_id, amount);
+
+        log!("Proceeds to withdraw: {}", amount);
+        self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+
+        self.internal_send_deposit_token(
+            sale_id,
+            beneficiary_id,
+            token_account_id.clone(),
+            amount,
+            format!("Proceeds {} of {}. Sale #{}", amount, token_account_id, sale_id),
+        )
+            .then(ext_self::after_withdraw_proceeds(
+                amount.into(),
+                token_account_id,
+                sale_id,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_AFTER_FT_TRANSFER,
+            ))
+    }
+
+    #[private]
+    pub fn after_withdraw_proceeds(&mut self, amount: U128, token_account_id: AccountId, sale_id: u64) -> bool {
+        let promise_success = is_promise_success();
+        if !promise_success {
+            let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+            sale.internal_rollback_token_withdraw(&token_account_id, amount.0);
+            sale.internal_rollback_near_payout(&token_account_id, amount.0);
+            self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+            log!("Proceeds withdraw for sale #{} failed. Tokens to recharge: {}", sale_id, amount.0);
+        }
+        promise_success
+    }
+
+    #[private]
+    pub fn update_sale_beneficiary_id(&mut self, sale_id: u64, beneficiary_id: AccountId) {
+        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        sale.beneficiary_id = Some(beneficiary_id);
+        self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+        emit_sale_update(sale_id, "beneficiary_id");
+    }
+
+    pub fn get_available_proceeds(&self, sale_id: u64, token_id: Option<AccountId>) -> U128 {
+        let sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        let token_id = token_id.unwrap_or_else(|| sale.deposit_token_id.clone());
+        U128(sale.get_available_token_proceeds(&token_id))
+    }
+}
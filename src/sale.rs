@@ -9,13 +9,28 @@ use near_sdk::json_types::{U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
 
 use crate::*;
+use crate::batch_auction::*;
+use crate::deposit_tokens::*;
+use crate::deposit_withdrawal::*;
+use crate::dutch_auction::*;
+use crate::finalize::*;
+use crate::events::*;
+use crate::lottery::*;
+use crate::native_near::*;
+use crate::price_oracle::*;
+use crate::price_tiers::*;
+use crate::settlement::*;
+use crate::stake_tiers::*;
+use crate::stats::*;
+use crate::swap::*;
 use crate::token_receiver::*;
+use crate::vesting::*;
 
-const ONE_YOCTO: Balance = 1;
-const GAS_NEAR_DEPOSIT: Gas = BASE_GAS;
-const GAS_AFTER_FT_ON_TRANSFER_NEAR_DEPOSIT: Gas = Gas(40_000_000_000_000);
-const GAS_FOR_FT_TRANSFER: Gas = Gas(10_000_000_000_000);
-const GAS_FOR_AFTER_FT_TRANSFER: Gas = Gas(10_000_000_000_000);
+pub(crate) const ONE_YOCTO: Balance = 1;
+pub(crate) const GAS_NEAR_DEPOSIT: Gas = BASE_GAS;
+pub(crate) const GAS_AFTER_FT_ON_TRANSFER_NEAR_DEPOSIT: Gas = Gas(40_000_000_000_000);
+pub(crate) const GAS_FOR_FT_TRANSFER: Gas = Gas(10_000_000_000_000);
+pub(crate) const GAS_FOR_AFTER_FT_TRANSFER: Gas = Gas(10_000_000_000_000);
 
 uint::construct_uint! {
     pub struct U256(4);
@@ -25,6 +40,8 @@ uint::construct_uint! {
 pub trait ExtWrapNear {
     /// Deposit NEAR to mint wNEAR tokens to the predecessor account in this contract.
     fn near_deposit(&mut self);
+    /// Burn wNEAR tokens of the predecessor and send it the NEAR.
+    fn near_withdraw(&mut self, amount: U128);
 }
 
 #[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
@@ -90,7 +107,33 @@ pub struct SaleInput {
     /// Limit per transaction
     pub limit_per_transaction: U128,
     /// Sale Type
-    pub sale_type: SaleType
+    pub sale_type: SaleType,
+    /// Release schedule of the purchased tokens. Everything is released at once if not set.
+    pub vesting: Option<VestingSchedule>,
+    /// Project treasury that receives the proceeds of the sale.
+    pub beneficiary_id: Option<AccountId>,
+    /// Price curve and supply. Only for sale_type: DutchAuction, `price` is the start price.
+    pub dutch_auction: Option<DutchAuctionConfig>,
+    /// Supply. Only for sale_type: BatchAuction, `price` is the reserve price.
+    pub batch_auction: Option<BatchAuctionConfig>,
+    /// Ordered price tiers by amount of distribute tokens sold. Only for sale_type: ByAmount
+    pub price_tiers: Option<Vec<PriceTier>>,
+    /// Registration phase and winners to draw. Only for sale_type: Lottery
+    pub lottery: Option<LotteryConfig>,
+    /// Ordered allocations by staked amount. Replace `max_buy` for the staking accounts.
+    pub stake_tiers: Option<Vec<StakeTier>>,
+    /// Minimum raise. Sale fails and refunds all the deposits if less is collected by end_date.
+    pub soft_cap: Option<U128>,
+    /// Part of the deposit kept on withdrawal, 1 => 0.01%. Only for sale_type: BySubscription
+    pub withdrawal_penalty: Option<u64>,
+    /// Tokens accepted besides deposit_token_id, with their rates.
+    pub deposit_tokens: Option<Vec<DepositTokenRate>>,
+    /// If set, price and caps are in USD and all the deposit tokens are valued by the oracle.
+    pub usd_oracle: Option<UsdOracleConfig>,
+    /// Exchange and the input tokens that can be swapped into deposit_token_id on deposit.
+    pub swap: Option<SwapConfig>,
+    /// Deposits are kept in native NEAR by the contract instead of wrap.near. deposit_token_id must be `near`.
+    pub native_near: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -118,13 +161,53 @@ pub struct SaleOutput {
     pub collected_amount: U128,
     pub num_account_sales: u64,
     pub sale_type: SaleType,
+    pub vesting: Option<VestingSchedule>,
+    pub beneficiary_id: Option<AccountId>,
+    pub withdrawn_amount: U128,
+    pub distribute_escrow_amount: U128,
+    pub dutch_auction: Option<DutchAuctionConfig>,
+    pub sold_amount: U128,
+    pub clearing_price: Option<U128>,
+    pub current_price: U128,
+    pub price_tiers: Option<Vec<PriceTier>>,
+    pub current_tier: Option<u64>,
+    pub stake_tiers: Option<Vec<StakeTier>>,
+    pub soft_cap: Option<U128>,
+    pub is_failed: bool,
+    pub cancelled: bool,
+    pub withdrawal_penalty: Option<u64>,
+    pub penalty_amount: U128,
+    pub status: SaleStatus,
+    pub result: Option<SaleResult>,
+    pub tags: Vec<String>,
+    pub category: Option<String>,
+    pub featured: bool,
+    pub deposit_tokens: Vec<DepositToken>,
+    pub usd_oracle: Option<UsdOracleConfig>,
+    pub swap: Option<SwapConfig>,
+    pub native_near: bool,
+    pub near_balance: U128,
 }
 
 /// Sale information.
-#[derive(BorshSerialize, BorshDeserialize)]
+#[derive(BorshSerialize)]
 pub enum VSale {
     First(SaleOld),
-    Current(Sale),
+    V1(SaleV1),
+    Current(Box<Sale>),
+}
+
+/// Derive needs `Box<Sale>` to be cloned on read, the collections of the sale can't be.
+/// Layout is the same as of the derived one.
+impl BorshDeserialize for VSale {
+    fn deserialize(buf: &mut &[u8]) -> std::io::Result<Self> {
+        match <u8 as BorshDeserialize>::deserialize(buf)? {
+            0 => Ok(VSale::First(BorshDeserialize::deserialize(buf)?)),
+            1 => Ok(VSale::V1(BorshDeserialize::deserialize(buf)?)),
+            2 => Ok(VSale::Current(Box::new(BorshDeserialize::deserialize(buf)?))),
+            _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "ERR_WRONG_SALE_VERSION")),
+        }
+    }
 }
 
 #[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, PartialEq)]
@@ -134,6 +217,13 @@ pub enum SaleType {
     ByAmount,
     /// Unlimited purchase, proportional distribution. Sale stops when end_date reached
     BySubscription,
+    /// Price falls over time. Deposits fill at the current price, all buyers settle at the final price.
+    /// Sale stops when end_date reached or supply is sold out
+    DutchAuction,
+    /// Bids with limit prices. After end_date all the bids settle at a single clearing price
+    BatchAuction,
+    /// Accounts register before the sale, only the drawn winners can buy a fixed ticket at `price`
+    Lottery,
 }
 
 #[derive(BorshSerialize, BorshDeserialize)]
@@ -156,6 +246,34 @@ pub struct SaleOld {
     pub account_sales: UnorderedMap<AccountId, VSaleAccount>,
 }
 
+/// Layout of the sales created before vesting was added.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct SaleV1 {
+    pub metadata: SaleMetadata,
+    pub staking_contracts: Vec<AccountId>,
+    pub min_near_deposit: Balance,
+    pub deposit_token_id: AccountId,
+    pub claim_available: bool,
+    pub refund_available: bool,
+    pub distribute_token_id: Option<AccountId>,
+    pub distribute_token_decimals: Option<u8>,
+    pub distribute_supply_amount: Option<Balance>,
+    pub min_buy: Balance,
+    pub max_buy: Balance,
+    pub max_amount: Balance,
+    pub hard_max_amount_limit: bool,
+    pub start_date: Timestamp,
+    pub end_date: Timestamp,
+    pub price: Balance,
+    pub whitelist_hash: Option<CryptoHash>,
+    pub limit_per_transaction: Balance,
+
+    pub collected_amount: Balance,
+    pub account_sales: UnorderedMap<AccountId, VSaleAccount>,
+    pub account_affiliate_rewards: UnorderedMap<AccountId, VAffiliateRewardAccount>,
+    pub sale_type: SaleType,
+}
+
 #[derive(BorshSerialize, BorshDeserialize)]
 pub struct Sale {
     pub metadata: SaleMetadata,
@@ -182,13 +300,50 @@ pub struct Sale {
     pub collected_amount: Balance,
     pub account_sales: UnorderedMap<AccountId, VSaleAccount>,
     pub account_affiliate_rewards: UnorderedMap<AccountId, VAffiliateRewardAccount>,
-    pub sale_type: SaleType
+    pub sale_type: SaleType,
+    pub vesting: Option<VestingSchedule>,
+    pub beneficiary_id: Option<AccountId>,
+    /// Total affiliate rewards recorded, in deposit tokens.
+    pub total_affiliate_rewards: Balance,
+    /// Proceeds already sent to the beneficiary.
+    pub withdrawn_amount: Balance,
+    /// Distribute tokens deposited by the project to pay out the claims.
+    pub distribute_escrow_amount: Balance,
+    pub dutch_auction: Option<DutchAuctionConfig>,
+    /// Distribute tokens sold so far. Only for sales filled at the deposit time price.
+    pub sold_amount: Balance,
+    /// Final price that all buyers settle at, once it is known.
+    pub clearing_price: Option<Balance>,
+    pub batch_auction: Option<BatchAuction>,
+    pub price_tiers: Option<Vec<PriceTier>>,
+    pub lottery: Option<Lottery>,
+    pub stake_tiers: Option<Vec<StakeTier>>,
+    pub soft_cap: Option<Balance>,
+    /// Cancelled by the owner. Deposits are closed and returned in full.
+    pub cancelled: bool,
+    pub withdrawal_penalty: Option<u64>,
+    /// Penalties kept from the withdrawn deposits, in deposit tokens.
+    pub penalty_amount: Balance,
+    /// Recorded by `finalize_sale`.
+    pub result: Option<SaleResult>,
+    /// Set by the owner for the sale views.
+    pub tags: Vec<String>,
+    pub category: Option<String>,
+    pub featured: bool,
+    /// None for the sales created before the counters were added.
+    pub stats: Option<SaleStats>,
+    pub deposit_tokens: Vec<DepositToken>,
+    pub usd_oracle: Option<UsdOracleConfig>,
+    pub swap: Option<SwapConfig>,
+    pub native_near: bool,
+    /// Native NEAR held by the contract for the sale.
+    pub near_balance: Balance,
 }
 
 impl From<VSale> for Sale {
     fn from(v_sale: VSale) -> Self {
         match v_sale {
-            VSale::First(sale) => Sale {
+            VSale::First(sale) => SaleV1 {
                 metadata: sale.metadata,
                 staking_contracts: sale.staking_contracts,
                 min_near_deposit: sale.min_near_deposit,
@@ -211,8 +366,64 @@ impl From<VSale> for Sale {
                 account_sales: sale.account_sales,
                 account_affiliate_rewards: UnorderedMap::new(StorageKey::AccountAffiliateRewards { sale_id: 0 }),
                 sale_type: SaleType::ByAmount,
-            },
-            VSale::Current(sale) => sale,
+            }.into(),
+            VSale::V1(sale) => sale.into(),
+            VSale::Current(sale) => *sale,
+        }
+    }
+}
+
+impl From<SaleV1> for Sale {
+    fn from(sale: SaleV1) -> Self {
+        Sale {
+            metadata: sale.metadata,
+            staking_contracts: sale.staking_contracts,
+            min_near_deposit: sale.min_near_deposit,
+            deposit_token_id: sale.deposit_token_id,
+            claim_available: sale.claim_available,
+            refund_available: sale.refund_available,
+            distribute_token_id: sale.distribute_token_id,
+            distribute_token_decimals: sale.distribute_token_decimals,
+            distribute_supply_amount: sale.distribute_supply_amount,
+            min_buy: sale.min_buy,
+            max_buy: sale.max_buy,
+            max_amount: sale.max_amount,
+            hard_max_amount_limit: sale.hard_max_amount_limit,
+            start_date: sale.start_date,
+            end_date: sale.end_date,
+            price: sale.price,
+            whitelist_hash: sale.whitelist_hash,
+            limit_per_transaction: sale.limit_per_transaction,
+            collected_amount: sale.collected_amount,
+            account_sales: sale.account_sales,
+            account_affiliate_rewards: sale.account_affiliate_rewards,
+            sale_type: sale.sale_type,
+            vesting: None,
+            beneficiary_id: None,
+            total_affiliate_rewards: 0,
+            withdrawn_amount: 0,
+            distribute_escrow_amount: 0,
+            dutch_auction: None,
+            sold_amount: 0,
+            clearing_price: None,
+            batch_auction: None,
+            price_tiers: None,
+            lottery: None,
+            stake_tiers: None,
+            soft_cap: None,
+            cancelled: false,
+            withdrawal_penalty: None,
+            penalty_amount: 0,
+            result: None,
+            tags: vec![],
+            category: None,
+            featured: false,
+            stats: None,
+            deposit_tokens: vec![],
+            usd_oracle: None,
+            swap: None,
+            native_near: false,
+            near_balance: 0,
         }
     }
 }
@@ -243,31 +454,92 @@ impl From<VSale> for SaleOutput {
                 collected_amount: U128(sale.collected_amount),
                 num_account_sales: sale.account_sales.keys_as_vector().len(),
                 sale_type: SaleType::ByAmount,
+                vesting: None,
+                beneficiary_id: None,
+                withdrawn_amount: U128(0),
+                distribute_escrow_amount: U128(0),
+                dutch_auction: None,
+                sold_amount: U128(0),
+                clearing_price: None,
+                current_price: U128(sale.price),
+                price_tiers: None,
+                current_tier: None,
+                stake_tiers: None,
+                soft_cap: None,
+                is_failed: false,
+                cancelled: false,
+                withdrawal_penalty: None,
+                penalty_amount: U128(0),
+                status: if env::block_timestamp() < sale.start_date {
+                    SaleStatus::Upcoming
+                } else if env::block_timestamp() > sale.end_date {
+                    SaleStatus::Ended
+                } else {
+                    SaleStatus::Active
+                },
+                result: None,
+                tags: vec![],
+                category: None,
+                featured: false,
+                deposit_tokens: vec![],
+                usd_oracle: None,
+                swap: None,
+                native_near: false,
+                near_balance: U128(0),
             },
-            VSale::Current(sale) => SaleOutput {
-                sale_id: None,
-                metadata: sale.metadata,
-                staking_contracts: sale.staking_contracts,
-                min_near_deposit: U128(sale.min_near_deposit),
-                deposit_token_id: sale.deposit_token_id,
-                claim_available: sale.claim_available,
-                refund_available: sale.refund_available,
-                distribute_token_id: sale.distribute_token_id,
-                distribute_token_decimals: sale.distribute_token_decimals,
-                distribute_supply_amount: Some(U128(sale.distribute_supply_amount.unwrap_or(0))),
-                min_buy: U128(sale.min_buy),
-                max_buy: U128(sale.max_buy),
-                max_amount: U128(sale.max_amount),
-                hard_max_amount_limit: sale.hard_max_amount_limit,
-                start_date: U64(sale.start_date),
-                end_date: U64(sale.end_date),
-                price: U128(sale.price),
-                whitelist_hash: sale.whitelist_hash,
-                limit_per_transaction: sale.limit_per_transaction.into(),
-                collected_amount: U128(sale.collected_amount),
-                num_account_sales: sale.account_sales.keys_as_vector().len(),
-                sale_type: sale.sale_type,
-            },
+            v_sale => {
+                let sale: Sale = v_sale.into();
+                SaleOutput {
+                    is_failed: sale.is_failed(),
+                    status: sale.get_status(env::block_timestamp()),
+                    current_price: U128(sale.get_current_price(env::block_timestamp())),
+                    current_tier: sale.get_current_tier().map(|tier| tier as u64),
+                    sale_id: None,
+                    metadata: sale.metadata,
+                    staking_contracts: sale.staking_contracts,
+                    min_near_deposit: U128(sale.min_near_deposit),
+                    deposit_token_id: sale.deposit_token_id,
+                    claim_available: sale.claim_available,
+                    refund_available: sale.refund_available,
+                    distribute_token_id: sale.distribute_token_id,
+                    distribute_token_decimals: sale.distribute_token_decimals,
+                    distribute_supply_amount: Some(U128(sale.distribute_supply_amount.unwrap_or(0))),
+                    min_buy: U128(sale.min_buy),
+                    max_buy: U128(sale.max_buy),
+                    max_amount: U128(sale.max_amount),
+                    hard_max_amount_limit: sale.hard_max_amount_limit,
+                    start_date: U64(sale.start_date),
+                    end_date: U64(sale.end_date),
+                    price: U128(sale.price),
+                    whitelist_hash: sale.whitelist_hash,
+                    limit_per_transaction: sale.limit_per_transaction.into(),
+                    collected_amount: U128(sale.collected_amount),
+                    num_account_sales: sale.account_sales.keys_as_vector().len(),
+                    sale_type: sale.sale_type,
+                    vesting: sale.vesting,
+                    beneficiary_id: sale.beneficiary_id,
+                    withdrawn_amount: U128(sale.withdrawn_amount),
+                    distribute_escrow_amount: U128(sale.distribute_escrow_amount),
+                    dutch_auction: sale.dutch_auction,
+                    sold_amount: U128(sale.sold_amount),
+                    clearing_price: sale.clearing_price.map(U128),
+                    price_tiers: sale.price_tiers,
+                    stake_tiers: sale.stake_tiers,
+                    soft_cap: sale.soft_cap.map(U128),
+                    cancelled: sale.cancelled,
+                    withdrawal_penalty: sale.withdrawal_penalty,
+                    penalty_amount: U128(sale.penalty_amount),
+                    result: sale.result,
+                    tags: sale.tags,
+                    category: sale.category,
+                    featured: sale.featured,
+                    deposit_tokens: sale.deposit_tokens,
+                    usd_oracle: sale.usd_oracle,
+                    swap: sale.swap,
+                    native_near: sale.native_near,
+                    near_balance: U128(sale.near_balance),
+                }
+            }
         }
     }
 }
@@ -280,10 +552,44 @@ impl VSale {
                     * U256::from(sale_input.max_amount.0)
                     / U256::from(u128::pow(10, sale_input.distribute_token_decimals.unwrap() as u32))
             ).as_u128())
+        } else if sale_input.sale_type == SaleType::DutchAuction {
+            Some(sale_input.dutch_auction.as_ref().expect("ERR_NO_DUTCH_AUCTION").supply_amount.0)
+        } else if sale_input.sale_type == SaleType::BatchAuction {
+            Some(sale_input.batch_auction.as_ref().expect("ERR_NO_BATCH_AUCTION").supply_amount.0)
+        } else {
+            None
+        };
+        let batch_auction = if sale_input.sale_type == SaleType::BatchAuction {
+            Some(BatchAuction::new(sale_id))
         } else {
             None
         };
-        Self::Current(Sale {
+        let mut deposit_tokens: Vec<DepositToken> = sale_input
+            .deposit_tokens
+            .unwrap_or_default()
+            .into_iter()
+            .map(DepositToken::new)
+            .collect();
+        // Sale priced in USD tracks deposit_token_id like the other tokens, at the last oracle rate.
+        if sale_input.usd_oracle.is_some() {
+            deposit_tokens.push(DepositToken::new(DepositTokenRate {
+                token_id: sale_input.deposit_token_id.clone(),
+                rate: U128(RATE_DENOMINATOR),
+            }));
+        }
+        if let Some(swap) = sale_input.swap.as_ref() {
+            let deposit_token_id = &sale_input.deposit_token_id;
+            assert!(
+                swap.pools.iter().all(|pool| &pool.token_id != deposit_token_id),
+                "ERR_WRONG_TOKEN"
+            );
+        }
+        let lottery = if sale_input.sale_type == SaleType::Lottery {
+            Some(Lottery::new(sale_id, sale_input.lottery.expect("ERR_NO_LOTTERY")))
+        } else {
+            None
+        };
+        Self::Current(Box::new(Sale {
             metadata: sale_input.metadata,
             staking_contracts: sale_input.staking_contracts,
             min_near_deposit: sale_input.min_near_deposit.0,
@@ -305,8 +611,34 @@ impl VSale {
             collected_amount: 0,
             account_sales: UnorderedMap::new(StorageKey::AccountSales { sale_id }),
             account_affiliate_rewards: UnorderedMap::new(StorageKey::AccountAffiliateRewards { sale_id }),
-            sale_type: sale_input.sale_type
-        })
+            sale_type: sale_input.sale_type,
+            vesting: sale_input.vesting,
+            beneficiary_id: sale_input.beneficiary_id,
+            total_affiliate_rewards: 0,
+            withdrawn_amount: 0,
+            distribute_escrow_amount: 0,
+            dutch_auction: sale_input.dutch_auction,
+            sold_amount: 0,
+            clearing_price: None,
+            batch_auction,
+            price_tiers: sale_input.price_tiers,
+            lottery,
+            stake_tiers: sale_input.stake_tiers,
+            soft_cap: sale_input.soft_cap.map(|soft_cap| soft_cap.0),
+            cancelled: false,
+            withdrawal_penalty: sale_input.withdrawal_penalty,
+            penalty_amount: 0,
+            result: None,
+            tags: vec![],
+            category: None,
+            featured: false,
+            stats: Some(SaleStats::new(sale_id)),
+            deposit_tokens,
+            usd_oracle: sale_input.usd_oracle,
+            swap: sale_input.swap,
+            native_near: sale_input.native_near.unwrap_or(false),
+            near_balance: 0,
+        }))
     }
 }
 
@@ -314,6 +646,7 @@ impl VSale {
 #[derive(BorshSerialize, BorshDeserialize)]
 pub enum VSaleAccount {
     First(SaleAccountOld),
+    V1(SaleAccountV1),
     Current(SaleAccount),
 }
 
@@ -330,6 +663,16 @@ pub struct SaleAccountOld {
     pub amount: U128,
 }
 
+/// Layout of the sale accounts created before batch auction bids were added.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct SaleAccountV1 {
+    pub amount: U128,
+    pub amount_to_claim: U128,
+    pub claimed: U128,
+    pub refund: U128,
+    pub refunded: U128,
+}
+
 #[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct SaleAccount {
@@ -338,18 +681,33 @@ pub struct SaleAccount {
     pub claimed: U128,
     pub refund: U128,
     pub refunded: U128,
+    /// Limit price of the bid. Only for sale_type: BatchAuction
+    pub limit_price: Option<U128>,
+    /// Set if the account paid with one of the deposit_tokens.
+    pub token_deposit: Option<TokenDeposit>,
 }
 
 impl From<VSaleAccount> for SaleAccount {
     fn from(v_account_sale: VSaleAccount) -> Self {
         match v_account_sale {
             VSaleAccount::Current(account_sale) => account_sale,
+            VSaleAccount::V1(account_sale) => SaleAccount {
+                amount: account_sale.amount,
+                amount_to_claim: account_sale.amount_to_claim,
+                claimed: account_sale.claimed,
+                refund: account_sale.refund,
+                refunded: account_sale.refunded,
+                limit_price: None,
+                token_deposit: None,
+            },
             VSaleAccount::First(account_sale) => SaleAccount {
                 amount: account_sale.amount,
                 amount_to_claim: U128(0),
                 claimed: U128(0),
                 refund: U128(0),
                 refunded: U128(0),
+                limit_price: None,
+                token_deposit: None,
             },
         }
     }
@@ -370,32 +728,84 @@ impl From<VAffiliateRewardAccount> for AffiliateRewardAccount {
     }
 }
 
+impl Sale {
+    /// Price of a single token at the given timestamp.
+    pub(crate) fn get_current_price(&self, timestamp: Timestamp) -> Balance {
+        match self.sale_type {
+            SaleType::ByAmount => {
+                match (&self.price_tiers, self.get_current_tier()) {
+                    (Some(price_tiers), Some(tier)) => price_tiers[tier].price.0,
+                    (Some(price_tiers), None) => price_tiers.last().unwrap().price.0,
+                    (None, _) => self.price,
+                }
+            }
+            SaleType::BySubscription | SaleType::Lottery => self.price,
+            SaleType::BatchAuction => self.clearing_price.unwrap_or(self.price),
+            SaleType::DutchAuction => {
+                if self.is_dutch_auction_done(timestamp) {
+                    self.get_dutch_auction_settlement_price()
+                } else {
+                    self.get_dutch_auction_price(timestamp)
+                }
+            }
+        }
+    }
+
+    /// Total amount of distribute tokens owed to the buyers.
+    pub(crate) fn get_total_allocation(&self) -> Balance {
+        if let Some(result) = &self.result {
+            result.total_allocation.0
+        } else if self.sale_type == SaleType::DutchAuction || self.sale_type == SaleType::BatchAuction || self.price_tiers.is_some() {
+            self.sold_amount
+        } else if self.collected_amount > 0 {
+            get_amount_to_claim(self, self.collected_amount)
+        } else {
+            0
+        }
+    }
+
+    /// Checks that distribute tokens in escrow are enough for all the purchases of the sale.
+    pub(crate) fn assert_escrow_covers_allocation(&self) {
+        let total_allocation = self.get_total_allocation();
+        assert!(self.distribute_escrow_amount >= total_allocation, "ERR_NOT_ENOUGH_ESCROW");
+    }
+}
+
 impl Contract {
-    fn get_sale_output(sale: VSale, sale_id: u64) -> SaleOutput {
+    pub(crate) fn get_sale_output(sale: VSale, sale_id: u64) -> SaleOutput {
         let mut output: SaleOutput = sale.into();
         output.sale_id = Some(sale_id);
         output
     }
 
     /// Validates deposit and records it for the given user for give sale.
-    /// Returns extra amount if sale is already over capacity.
-    pub(crate) fn internal_sale_deposit(
-        &mut self,
-        sale_id: u64,
-        token_id: &AccountId,
-        sender_id: &AccountId,
-        staked_amount: Balance,
-        amount: Balance,
-    ) -> Balance {
+    /// Returns extra amount if sale is already over capacity or account is over max_buy.
+    pub(crate) fn internal_sale_deposit(&mut self, deposit: &PendingDeposit, staked_amount: Balance) -> Balance {
+        let sale_id = deposit.sale_id;
+        let token_id = &deposit.token_id;
+        let sender_id = &deposit.sender_id;
+        let token_amount = deposit.amount.0;
+        let max_buy = deposit.max_buy.0;
+        let limit_price = deposit.limit_price.map(|price| price.0);
+        let initial_storage_usage = env::storage_usage();
         let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
-        assert_eq!(&sale.deposit_token_id, token_id, "ERR_WRONG_TOKEN");
+        assert!(!sale.cancelled, "ERR_SALE_CANCELLED");
+        sale.assert_not_finalized();
+        let amount = sale.get_deposit_units(token_id, token_amount);
         assert!(amount <= sale.limit_per_transaction, "ERR_LIMIT_PER_TX");
         assert!(
             staked_amount >= sale.min_near_deposit,
             "ERR_NOT_ENOUGH_STAKED"
         );
-        // TODO: add check for the whitelist hash.
-        let deposit_amount = if !sale.hard_max_amount_limit {
+        let max_buy = if sale.stake_tiers.is_some() {
+            std::cmp::min(
+                max_buy,
+                sale.get_stake_allocation(staked_amount).expect("ERR_NOT_ENOUGH_STAKED"),
+            )
+        } else {
+            max_buy
+        };
+        let mut deposit_amount = if !sale.hard_max_amount_limit {
             amount
         } else {
             std::cmp::min(
@@ -413,37 +823,76 @@ impl Contract {
                 claimed: U128(0),
                 refund: U128(0),
                 refunded: U128(0),
+                limit_price: None,
+                token_deposit: None,
             });
-        account_sale.amount = U128(account_sale.amount.0 + deposit_amount);
-        assert!(
-            sale.max_buy >= account_sale.amount.0 && sale.min_buy <= account_sale.amount.0,
-            "ERR_WRONG_AMOUNT"
-        );
-
-        let fees = self.referral_fees.clone();
-        if let Some(referrer_v_account_1) = self.accounts.get(&sender_id) {
-            let referrer_account_1: Account = referrer_v_account_1.into();
-            let reward_1 = deposit_amount * fees[0] as u128 / REFERRAL_FEE_DENOMINATOR;
-            self.internal_insert_affiliate(&mut sale, &referrer_account_1.referrer, reward_1);
-            if let Some(referrer_v_account_2) = self.accounts.get(&referrer_account_1.referrer) {
-                let referrer_account_2: Account = referrer_v_account_2.into();
-                let reward_2 = deposit_amount * fees[1] as u128 / REFERRAL_FEE_DENOMINATOR;
-                self.internal_insert_affiliate(&mut sale, &referrer_account_2.referrer, reward_2);
-                if let Some(referrer_v_account_3) = self.accounts.get(&referrer_account_2.referrer) {
-                    let referrer_account_3: Account = referrer_v_account_3.into();
-                    let reward_3 = deposit_amount * fees[2] as u128 / REFERRAL_FEE_DENOMINATOR;
-                    self.internal_insert_affiliate(&mut sale, &referrer_account_3.referrer, reward_3);
-                }
-            }
+        // Accept only the part that fits under max_buy, the rest is returned as unused.
+        deposit_amount = std::cmp::min(deposit_amount, max_buy.saturating_sub(account_sale.amount.0));
+        assert_ne!(deposit_amount, 0, "ERR_WRONG_AMOUNT");
+        if sale.price_tiers.is_some() {
+            let (used_amount, tokens) = sale.internal_tiered_fill(deposit_amount);
+            log!("Filled {} tokens for {}", tokens, used_amount);
+            deposit_amount = used_amount;
+            account_sale.amount_to_claim = U128(account_sale.amount_to_claim.0 + tokens);
+        }
+        if sale.sale_type == SaleType::DutchAuction {
+            let (used_amount, tokens) = sale.internal_dutch_auction_fill(deposit_amount, env::block_timestamp());
+            log!("Filled {} tokens for {}", tokens, used_amount);
+            deposit_amount = used_amount;
+            account_sale.amount_to_claim = U128(account_sale.amount_to_claim.0 + tokens);
+        }
+        if sale.sale_type == SaleType::BatchAuction {
+            let limit_price = limit_price.expect("ERR_MUST_HAVE_LIMIT_PRICE");
+            sale.internal_batch_auction_bid(&mut account_sale, deposit_amount, limit_price);
+        }
+        let used_token_amount = sale.get_used_token_amount(token_id, token_amount, deposit_amount);
+        sale.internal_record_token_deposit(&mut account_sale, token_id, used_token_amount, deposit_amount);
+        let old_amount = account_sale.amount.0;
+        account_sale.amount = U128(old_amount + deposit_amount);
+        assert!(sale.min_buy <= account_sale.amount.0, "ERR_WRONG_AMOUNT");
+        if let Some(lottery) = &sale.lottery {
+            // Winners buy exactly one ticket, the excess is returned as unused.
+            assert_eq!(account_sale.amount.0, lottery.ticket_size, "ERR_WRONG_TICKET_SIZE");
+        }
+        self.internal_update_deposit_stats(&mut sale, sender_id, old_amount, account_sale.amount.0);
+        Event::DepositAccept(vec![DepositData {
+            sale_id,
+            account_id: sender_id,
+            accepted: U128(used_token_amount),
+            refunded: U128(token_amount - used_token_amount),
+            usd_value: sale.usd_oracle.as_ref().map(|_| U128(deposit_amount)),
+            rate: sale.usd_oracle.as_ref().map(|_| sale.get_deposit_token_rate(token_id)),
+        }]).emit();
+
+        for (referrer_id, reward) in self.get_affiliate_rewards(sender_id, deposit_amount) {
+            self.internal_insert_affiliate(sale_id, &mut sale, &referrer_id, reward);
         }
 
         sale.account_sales.insert(&sender_id, &VSaleAccount::Current(account_sale));
         sale.collected_amount += deposit_amount;
-        self.sales.insert(&sale_id, &VSale::Current(sale));
-        amount - deposit_amount
+        self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+        self.internal_add_to_portfolio(sender_id, sale_id);
+        self.internal_charge_storage(sender_id, initial_storage_usage);
+        token_amount - used_token_amount
     }
 
-    pub(crate) fn internal_insert_affiliate(&mut self, sale: &mut Sale, account_id: &AccountId, amount: u128) {
+    /// Referrers of up to 3 levels of the account and their rewards for the given deposit.
+    pub(crate) fn get_affiliate_rewards(&self, account_id: &AccountId, deposit_amount: Balance) -> Vec<(AccountId, Balance)> {
+        let mut rewards = vec![];
+        let mut account_id = account_id.clone();
+        for fee in self.referral_fees.iter() {
+            if let Some(v_account) = self.accounts.get(&account_id) {
+                let account: Account = v_account.into();
+                rewards.push((account.referrer.clone(), deposit_amount * *fee as u128 / REFERRAL_FEE_DENOMINATOR));
+                account_id = account.referrer;
+            } else {
+                break;
+            }
+        }
+        rewards
+    }
+
+    pub(crate) fn internal_insert_affiliate(&mut self, sale_id: u64, sale: &mut Sale, account_id: &AccountId, amount: u128) {
         let account_affiliate_reward =
             if let Some(v_account_affiliate_reward) = sale.account_affiliate_rewards.get(account_id) {
                 let mut account_affiliate_reward: AffiliateRewardAccount = v_account_affiliate_reward.into();
@@ -457,6 +906,23 @@ impl Contract {
             };
 
         sale.account_affiliate_rewards.insert(&account_id, &VAffiliateRewardAccount::Current(account_affiliate_reward));
+        sale.total_affiliate_rewards += amount;
+        Event::AffiliateReward(vec![AmountData {
+            sale_id,
+            account_id,
+            amount: U128(amount),
+        }]).emit();
+    }
+
+    pub(crate) fn internal_remove_affiliate(&mut self, sale: &mut Sale, account_id: &AccountId, amount: u128) {
+        if let Some(v_account_affiliate_reward) = sale.account_affiliate_rewards.get(account_id) {
+            let mut account_affiliate_reward: AffiliateRewardAccount = v_account_affiliate_reward.into();
+            // Rewards are rounded down per deposit, never remove more than recorded.
+            let amount = std::cmp::min(amount, account_affiliate_reward.amount.0);
+            account_affiliate_reward.amount = U128::from(account_affiliate_reward.amount.0 - amount);
+            sale.account_affiliate_rewards.insert(&account_id, &VAffiliateRewardAccount::Current(account_affiliate_reward));
+            sale.total_affiliate_rewards -= amount;
+        }
     }
 
     pub(crate) fn internal_finalize_near_deposit(
@@ -464,20 +930,20 @@ impl Contract {
         return_amount: Balance,
         sender_id: AccountId,
         deposit_amount: Balance,
+        sale_id: u64,
     ) -> PromiseOrValue<U128> {
-        let wrap_amount = deposit_amount - return_amount;
-        if wrap_amount > 0 {
-            // Assuming it will succeed
-            ext_wrap_near::near_deposit(
-                AccountId::new_unchecked(WRAP_NEAR_ACCOUNT.to_string()),
-                wrap_amount,
-                GAS_NEAR_DEPOSIT,
-            );
+        let accepted_amount = deposit_amount - return_amount;
+        let sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        if accepted_amount > 0 && sale.native_near {
+            self.internal_record_near_deposit(sale_id, accepted_amount);
         }
-        if return_amount > 0 {
-            Promise::new(sender_id).transfer(return_amount).into()
-        } else {
+        if return_amount == 0 {
             PromiseOrValue::Value(U128(0))
+        } else if sale.native_near {
+            self.internal_return_near(sender_id, return_amount).into()
+        } else {
+            // NEAR was wrapped before the deposit.
+            self.internal_unwrap_and_return_near(sender_id, return_amount).into()
         }
     }
 }
@@ -517,10 +983,18 @@ impl Contract {
         if let Some(sale_account) = sale.account_sales.get(&account_id) {
             let sale_account: SaleAccount = sale_account.into();
             match sale.sale_type {
-                SaleType::ByAmount => sale_account.amount,
+                SaleType::ByAmount | SaleType::DutchAuction | SaleType::Lottery => sale_account.amount,
+                SaleType::BatchAuction => {
+                    let refund = if sale.clearing_price.is_some() {
+                        sale.get_batch_auction_fill(&sale_account).1
+                    } else {
+                        0
+                    };
+                    U128(sale_account.amount.0 - refund)
+                }
                 SaleType::BySubscription => {
                     U128::from(
-                        get_amount_by_subscription(sale_account.amount.0, sale.collected_amount, sale.distribute_supply_amount.expect("ERR_MUST_HAVE_SUPPLY_AMOUNT"))
+                        get_amount_by_subscription(sale_account.amount.0, sale.get_collected_amount(), sale.distribute_supply_amount.expect("ERR_MUST_HAVE_SUPPLY_AMOUNT"))
                     )
                 }
             }
@@ -533,61 +1007,25 @@ impl Contract {
         let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
 
         let account_id = env::predecessor_account_id();
-        let distribute_token_decimals = sale.distribute_token_decimals.expect("ERR_NO_TOKEN_DECIMALS");
+        assert!(sale.distribute_token_decimals.is_some(), "ERR_NO_TOKEN_DECIMALS");
 
         if let Some(v_sale_account) = sale.account_sales.get(&account_id) {
             let mut account_sale: SaleAccount = v_sale_account.into();
 
             assert_ne!(account_sale.amount.0, 0, "ERR_NO_ALLOCATION");
+            if sale.sale_type == SaleType::BatchAuction {
+                assert!(sale.clearing_price.is_some(), "ERR_AUCTION_NOT_SETTLED");
+            }
 
-            let deposit_amount = account_sale.amount.0;
-
-            let amount_to_claim: u128 =
-                if account_sale.claimed.0 == 0 {
-                    let total_amount_to_claim: u128 = (
-                        U256::from(u128::pow(10, distribute_token_decimals as u32))
-                            * U256::from(deposit_amount)
-                            / U256::from(sale.price)
-                    ).as_u128();
-
-                    let total_filled_amount: u128 = (
-                        U256::from(u128::pow(10, distribute_token_decimals as u32))
-                            * U256::from(sale.collected_amount)
-                            / U256::from(sale.price)
-                    ).as_u128();
-
-                    match sale.sale_type {
-                        SaleType::ByAmount => total_amount_to_claim,
-                        SaleType::BySubscription => {
-                            if sale.max_amount >= sale.collected_amount {
-                                total_amount_to_claim
-                            } else {
-                                get_amount_by_subscription(total_amount_to_claim, total_filled_amount, sale.distribute_supply_amount.expect("ERR_MUST_HAVE_SUPPLY_AMOUNT"))
-                            }
-                        }
-                    }
-                } else {
-                    account_sale.claimed.0
-                };
+            let (amount_to_claim, refund) = sale.get_account_settlement(&account_sale);
 
             if account_sale.amount_to_claim.0 == 0 && amount_to_claim > 0 {
                 account_sale.amount_to_claim = U128(amount_to_claim);
             }
-
-            if sale.sale_type == SaleType::BySubscription {
-                let client_purchase_amount: u128 = (
-                    U256::from(amount_to_claim)
-                        * U256::from(sale.price)
-                        / U256::from(u128::pow(10, distribute_token_decimals as u32))
-                ).as_u128();
-
-                if account_sale.refund.0 == 0 && deposit_amount > client_purchase_amount {
-                    account_sale.refund = U128(deposit_amount - client_purchase_amount);
-                }
-            }
+            account_sale.refund = U128(refund);
 
             sale.account_sales.insert(&account_id, &VSaleAccount::Current(account_sale));
-            self.sales.insert(&sale_id, &VSale::Current(sale));
+            self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
         } else {
             panic!("ERR_NO_DATA");
         }
@@ -602,7 +1040,16 @@ impl Contract {
             assert!(env::block_timestamp() > sale.end_date, "ERR_SALE_IN_PROGRESS");
         }
 
+        assert!(!sale.cancelled, "ERR_SALE_CANCELLED");
+        assert!(!sale.is_failed(), "ERR_SALE_FAILED");
+        if sale.result.is_none() {
+            sale = self.internal_finalize_sale(sale_id);
+        }
         let distribute_token_id = sale.distribute_token_id.clone().expect("ERR_NO_TOKEN_ID");
+        sale.assert_escrow_covers_allocation();
+        if sale.sale_type == SaleType::DutchAuction {
+            assert!(sale.is_dutch_auction_done(env::block_timestamp()), "ERR_SALE_IN_PROGRESS");
+        }
 
         let account_id = env::predecessor_account_id();
 
@@ -611,17 +1058,18 @@ impl Contract {
         if let Some(v_sale_account) = sale.account_sales.get(&account_id) {
             let mut account_sale: SaleAccount = v_sale_account.into();
 
-            assert_eq!(account_sale.claimed.0, 0, "ERR_ALREADY_CLAIMED");
+            let vested_amount = get_vested_amount(&sale, account_sale.amount_to_claim.0, env::block_timestamp());
+            assert!(account_sale.claimed.0 < account_sale.amount_to_claim.0, "ERR_ALREADY_CLAIMED");
 
-            let amount_to_claim = account_sale.amount_to_claim;
+            let amount_to_claim = U128(vested_amount.saturating_sub(account_sale.claimed.0));
 
             assert_ne!(amount_to_claim.0, 0, "ERR_NOTHING_TO_CLAIM");
-            account_sale.claimed = amount_to_claim;
+            account_sale.claimed = U128(account_sale.claimed.0 + amount_to_claim.0);
 
             log!("Amount to claim: {}", amount_to_claim.0);
 
             sale.account_sales.insert(&account_id, &VSaleAccount::Current(account_sale));
-            self.sales.insert(&sale_id, &VSale::Current(sale));
+            self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
 
             self.withdraw_purchase(account_id,
                                    amount_to_claim.0,
@@ -635,8 +1083,20 @@ impl Contract {
 
     pub fn claim_refund(&mut self, sale_id: u64) -> Promise {
         let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
-        assert!(sale.sale_type == SaleType::BySubscription, "ERR_REFUND_NOT_ALLOWED");
+        if sale.is_full_refund() {
+            return self.internal_claim_full_refund(sale, sale_id, env::predecessor_account_id());
+        }
+        assert!(
+            sale.sale_type != SaleType::ByAmount && sale.sale_type != SaleType::Lottery,
+            "ERR_REFUND_NOT_ALLOWED"
+        );
         assert!(sale.refund_available, "ERR_REFUND_NOT_AVAILABLE");
+        if sale.result.is_none() {
+            sale = self.internal_finalize_sale(sale_id);
+        }
+        if sale.sale_type == SaleType::DutchAuction {
+            assert!(sale.is_dutch_auction_done(env::block_timestamp()), "ERR_SALE_IN_PROGRESS");
+        }
 
         if DISABLE_CLAIM_DURING_SALE {
             assert!(env::block_timestamp() > sale.end_date, "ERR_SALE_IN_PROGRESS");
@@ -651,21 +1111,27 @@ impl Contract {
 
             assert_ne!(account_sale.refund.0, 0, "ERR_NOTHING_TO_REFUND");
             assert_eq!(account_sale.refunded.0, 0, "ERR_ALREADY_REFUNDED");
-            assert_ne!(account_sale.claimed.0, 0, "ERR_MUST_CLAIM_BEFORE_REFUND");
+            // Accounts without allocation, like the outbid batch auction bidders, have nothing to claim.
+            // Before TGE nothing is vested yet, the refund doesn't wait for it.
+            let vested_amount = get_vested_amount(&sale, account_sale.amount_to_claim.0, env::block_timestamp());
+            if vested_amount > 0 {
+                assert_ne!(account_sale.claimed.0, 0, "ERR_MUST_CLAIM_BEFORE_REFUND");
+            }
 
             let amount_to_refund: U128 = account_sale.refund;
             account_sale.refunded = amount_to_refund;
 
             log!("Amount to refund: {}", amount_to_refund.0);
-            let token_account_id = sale.deposit_token_id.clone();
 
             sale.account_sales
                 .insert(&account_id, &VSaleAccount::Current(account_sale));
-            self.sales.insert(&sale_id, &VSale::Current(sale));
+            let (token_account_id, token_amount) = sale.internal_record_token_refund(&account_id, amount_to_refund.0);
+            self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
 
             self.refund_purchase(account_id,
                                  amount_to_refund.0,
                                  token_account_id,
+                                 token_amount,
                                  sale_id)
         } else {
             panic!("ERR_NO_DATA");
@@ -674,12 +1140,17 @@ impl Contract {
 
     pub fn claim_affiliate_reward(&mut self, sale_id: u64) -> Promise {
         let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
-        let distribute_token_decimals = sale.distribute_token_decimals.expect("ERR_NO_TOKEN_DECIMALS");
+        assert!(sale.distribute_token_decimals.is_some(), "ERR_NO_TOKEN_DECIMALS");
         let account_id = env::predecessor_account_id();
 
         assert!(sale.refund_available, "ERR_NOT_AVAILABLE");
+        assert!(!sale.cancelled, "ERR_SALE_CANCELLED");
+        assert!(!sale.is_failed(), "ERR_SALE_FAILED");
 
-        assert!(sale.sale_type == SaleType::BySubscription && sale.max_amount < sale.collected_amount, "SALE_BY_SUBSCRIPTION_FAILED");
+        assert!(sale.is_affiliate_reward_available(), "SALE_BY_SUBSCRIPTION_FAILED");
+        if sale.result.is_none() {
+            sale = self.internal_finalize_sale(sale_id);
+        }
 
         if DISABLE_CLAIM_DURING_SALE {
             assert!(env::block_timestamp() > sale.end_date, "ERR_SALE_IN_PROGRESS");
@@ -691,26 +1162,7 @@ impl Contract {
             assert_ne!(account_affiliate_reward.amount.0, 0, "ERR_NOTHING_TO_CLAIM");
             assert_eq!(account_affiliate_reward.claimed.0, 0, "ERR_ALREADY_CLAIMED");
 
-            let deposit_amount = account_affiliate_reward.amount.0;
-
-            let total_amount_to_claim: u128 = (
-                U256::from(u128::pow(10, distribute_token_decimals as u32))
-                    * U256::from(deposit_amount)
-                    / U256::from(sale.price)
-            ).as_u128();
-
-            let total_filled_amount: u128 = (
-                U256::from(u128::pow(10, distribute_token_decimals as u32))
-                    * U256::from(sale.collected_amount)
-                    / U256::from(sale.price)
-            ).as_u128();
-
-            let amount_to_claim: u128 = match sale.sale_type {
-                SaleType::ByAmount => total_amount_to_claim,
-                SaleType::BySubscription => {
-                    get_amount_by_subscription(total_amount_to_claim, total_filled_amount, sale.distribute_supply_amount.expect("ERR_MUST_HAVE_SUPPLY_AMOUNT"))
-                }
-            };
+            let amount_to_claim = get_affiliate_reward_amount(&sale, account_affiliate_reward.amount.0);
 
             assert_ne!(amount_to_claim, 0, "ERR_NOTHING_TO_CLAIM");
             log!("Amount to claim: {}", amount_to_claim);
@@ -720,7 +1172,7 @@ impl Contract {
             let deposit_token_id = sale.deposit_token_id.clone();
 
             sale.account_affiliate_rewards.insert(&account_id, &VAffiliateRewardAccount::Current(account_affiliate_reward));
-            self.sales.insert(&sale_id, &VSale::Current(sale));
+            self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
 
             self.withdraw_affiliate_reward(account_id, amount_to_claim, deposit_token_id, sale_id)
         } else {
@@ -731,21 +1183,30 @@ impl Contract {
     #[payable]
     pub fn deposit_near(&mut self, sale_deposit: SaleDeposit) -> PromiseOrValue<U128> {
         let sender_id = env::predecessor_account_id();
-        let token_id = AccountId::new_unchecked(WRAP_NEAR_ACCOUNT.to_string());
+        let sale: Sale = self.sales.get(&sale_deposit.sale_id).expect("ERR_NO_SALE").into();
+        let sale_id = sale_deposit.sale_id;
         let amount = env::attached_deposit();
-        match self.internal_ft_on_transfer(token_id, sender_id.clone(), amount.into(), sale_deposit)
+        assert!(sale_deposit.swap.is_none(), "ERR_SWAP_NOT_ALLOWED");
+        // Native NEAR sales keep the deposit, others record it as wrap.near once it is wrapped.
+        if !sale.native_near {
+            let _ = self.accounts.get(&sender_id).expect("ERR_NOT_REGISTERED_ACCOUNT");
+            self.assert_storage_registered(&sender_id);
+            return self.internal_wrap_near_deposit(&sale, sender_id, amount, sale_deposit).into();
+        }
+        match self.internal_ft_on_transfer(sale.deposit_token_id, sender_id.clone(), amount.into(), sale_deposit)
         {
             PromiseOrValue::Promise(promise) => promise
                 .then(ext_self::after_ft_on_transfer_near_deposit(
                     sender_id,
                     U128(amount),
+                    sale_id,
                     env::current_account_id(),
                     NO_DEPOSIT,
                     GAS_AFTER_FT_ON_TRANSFER_NEAR_DEPOSIT,
                 ))
                 .into(),
             PromiseOrValue::Value(value) => {
-                self.internal_finalize_near_deposit(value.0, sender_id, amount)
+                self.internal_finalize_near_deposit(value.0, sender_id, amount, sale_id)
             }
         }
     }
@@ -764,11 +1225,54 @@ impl Contract {
         );
 
         assert!(sale.distribute_token_decimals.expect("ERR_NO_TOKEN_DECIMALS") > 0, "WRONG_DECIMALS");
+        if let Some(vesting) = &sale.vesting {
+            vesting.assert_valid();
+        }
+        if sale.sale_type == SaleType::DutchAuction {
+            sale.dutch_auction.as_ref().expect("ERR_NO_DUTCH_AUCTION").assert_valid(sale.price.0);
+        }
+        if let Some(price_tiers) = &sale.price_tiers {
+            assert!(sale.sale_type == SaleType::ByAmount, "ERR_PRICE_TIERS_NOT_ALLOWED");
+            assert_valid_price_tiers(price_tiers);
+        }
+        if sale.sale_type == SaleType::BatchAuction {
+            assert_ne!(sale.price.0, 0, "ERR_NO_SALE_PRICE");
+            assert_ne!(sale.batch_auction.as_ref().expect("ERR_NO_BATCH_AUCTION").supply_amount.0, 0, "ERR_MUST_HAVE_SUPPLY_AMOUNT");
+        }
+        if let Some(withdrawal_penalty) = sale.withdrawal_penalty {
+            assert!(sale.sale_type == SaleType::BySubscription, "ERR_WITHDRAWAL_PENALTY_NOT_ALLOWED");
+            assert!((withdrawal_penalty as u128) < WITHDRAWAL_PENALTY_DENOMINATOR, "ERR_WRONG_WITHDRAWAL_PENALTY");
+        }
+        if let Some(soft_cap) = sale.soft_cap {
+            assert_ne!(soft_cap.0, 0, "ERR_WRONG_SOFT_CAP");
+            assert!(!sale.hard_max_amount_limit || soft_cap.0 <= sale.max_amount.0, "ERR_WRONG_SOFT_CAP");
+        }
+        if let Some(stake_tiers) = &sale.stake_tiers {
+            assert!(!sale.staking_contracts.is_empty(), "ERR_MUST_HAVE_STAKING_CONTRACT");
+            assert_valid_stake_tiers(stake_tiers);
+        }
+        if sale.sale_type == SaleType::Lottery {
+            let lottery = sale.lottery.as_ref().expect("ERR_NO_LOTTERY");
+            assert!(lottery.registration_end.0 <= sale.start_date.0, "ERR_WRONG_REGISTRATION_DATES");
+        }
+        assert_eq!(
+            sale.native_near.unwrap_or(false),
+            is_native_near_token(&sale.deposit_token_id),
+            "ERR_WRONG_TOKEN"
+        );
+        if sale.native_near.unwrap_or(false) {
+            assert!(sale.swap.is_none(), "ERR_SWAP_NOT_ALLOWED");
+        }
+        assert!(
+            sale.deposit_tokens.iter().flatten().all(|deposit_token| !is_native_near_token(&deposit_token.token_id)),
+            "ERR_WRONG_TOKEN"
+        );
 
         self.sales
             .insert(&self.num_sales, &VSale::new(self.num_sales, sale));
         let sale_id = self.num_sales;
         self.num_sales += 1;
+        Event::SaleCreate(vec![SaleData { sale_id }]).emit();
         sale_id
     }
 
@@ -788,13 +1292,15 @@ impl Contract {
     #[private]
     pub fn update_sale_dates(&mut self, sale_id: u64, start_date: U64, end_date: U64) {
         let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        sale.assert_not_finalized();
         assert!(
             sale.collected_amount < sale.max_amount,
             "ERR_SALE_DONE"
         );
         sale.start_date = start_date.into();
         sale.end_date = end_date.into();
-        self.sales.insert(&sale_id, &VSale::Current(sale));
+        self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+        emit_sale_update(sale_id, "dates");
     }
 
     #[private]
@@ -802,7 +1308,8 @@ impl Contract {
         let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
         assert!(sale.distribute_token_id.is_none(), "ERR_ALREADY_SET");
         sale.distribute_token_id = Some(distribute_token_id);
-        self.sales.insert(&sale_id, &VSale::Current(sale));
+        self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+        emit_sale_update(sale_id, "distribute_token_id");
     }
 
     #[private]
@@ -810,7 +1317,8 @@ impl Contract {
         let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
         assert!(sale.distribute_token_decimals.is_none(), "ERR_ALREADY_SET");
         sale.distribute_token_decimals = Some(distribute_token_decimals);
-        self.sales.insert(&sale_id, &VSale::Current(sale));
+        self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+        emit_sale_update(sale_id, "distribute_token_decimals");
     }
 
     #[private]
@@ -818,8 +1326,12 @@ impl Contract {
         let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
         assert!(sale.distribute_token_id.is_some(), "ERR_NOT_ENOUGH_DATA");
         assert!(sale.distribute_token_decimals.is_some(), "ERR_NOT_ENOUGH_DATA");
+        if claim_available {
+            sale.assert_escrow_covers_allocation();
+        }
         sale.claim_available = claim_available;
-        self.sales.insert(&sale_id, &VSale::Current(sale));
+        self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+        emit_sale_update(sale_id, "claim_available");
     }
 
     #[private]
@@ -828,7 +1340,8 @@ impl Contract {
         assert!(sale.distribute_token_id.is_some(), "ERR_NOT_ENOUGH_DATA");
         assert!(sale.distribute_token_decimals.is_some(), "ERR_NOT_ENOUGH_DATA");
         sale.refund_available = refund_available;
-        self.sales.insert(&sale_id, &VSale::Current(sale));
+        self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+        emit_sale_update(sale_id, "refund_available");
     }
 
 
@@ -863,7 +1376,14 @@ impl Contract {
     pub fn get_sale_account(&self, sale_id: u64, account_id: AccountId) -> SaleAccount {
         let sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
         if let Some(sale_account) = sale.account_sales.get(&account_id) {
-            sale_account.into()
+            let mut sale_account: SaleAccount = sale_account.into();
+            // Fill of the batch auction is known as soon as it's settled.
+            if sale.sale_type == SaleType::BatchAuction && sale.clearing_price.is_some() {
+                let (tokens, refund) = sale.get_batch_auction_fill(&sale_account);
+                sale_account.amount_to_claim = U128(tokens);
+                sale_account.refund = U128(refund);
+            }
+            sale_account
         } else {
             SaleAccount {
                 amount: U128(0),
@@ -871,6 +1391,8 @@ impl Contract {
                 claimed: U128(0),
                 refund: U128(0),
                 refunded: U128(0),
+                limit_price: None,
+                token_deposit: None,
             }
         }
     }
@@ -890,24 +1412,16 @@ impl Contract {
     pub fn on_get_account_staked_balance(
         &mut self,
         #[callback] staked_amount: U128,
-        sale_id: u64,
-        token_id: AccountId,
-        sender_id: AccountId,
-        deposit_amount: U128,
+        deposit: PendingDeposit,
     ) -> PromiseOrValue<U128> {
         assert_eq!(
             env::predecessor_account_id(),
             env::current_account_id(),
             "ERR_NOT_OWNER"
         );
-        log!("{} stake: {}", sender_id, staked_amount.0);
-        PromiseOrValue::Value(U128(self.internal_sale_deposit(
-            sale_id,
-            &token_id,
-            &sender_id,
-            staked_amount.0,
-            deposit_amount.0,
-        )))
+        log!("{} stake: {}", deposit.sender_id, staked_amount.0);
+        let sale: Sale = self.sales.get(&deposit.sale_id).expect("ERR_NO_SALE").into();
+        self.internal_deposit_with_price(&sale, deposit, staked_amount.0)
     }
 
     #[private]
@@ -916,11 +1430,14 @@ impl Contract {
         #[callback_result] return_amount: Result<U128, PromiseError>,
         sender_id: AccountId,
         deposit_amount: U128,
+        sale_id: u64,
     ) -> PromiseOrValue<U128> {
+        // Failed deposit credited nothing, the whole deposit is returned.
         self.internal_finalize_near_deposit(
-            return_amount.map(|v| v.0).unwrap_or(0),
+            return_amount.map(|v| v.0).unwrap_or(deposit_amount.0),
             sender_id,
             deposit_amount.0,
+            sale_id,
         )
     }
 
@@ -955,7 +1472,14 @@ impl Contract {
         sale_id: u64,
     ) -> bool {
         let promise_success = is_promise_success();
-        if !promise_success {
+        if promise_success {
+            Event::Claim(vec![AmountData {
+                sale_id,
+                account_id: &account_id,
+                amount: amount_to_claim,
+            }]).emit();
+            self.internal_update_sale_stats(sale_id, |stats| stats.total_claimed += amount_to_claim.0);
+        } else {
             let mut sale: Sale = self
                 .sales
                 .get(&sale_id)
@@ -966,29 +1490,34 @@ impl Contract {
                 let mut account_sale: SaleAccount = v_sale_account.into();
                 account_sale.claimed = U128::from(account_sale.claimed.0 - amount_to_claim.0);
                 sale.account_sales.insert(&account_id, &VSaleAccount::Current(account_sale));
-                self.sales.insert(&sale_id, &VSale::Current(sale));
+                self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
                 log!("Purchase withdraw for {} failed. Tokens to recharge: {}",account_id, amount_to_claim.0);
             }
         }
         promise_success
     }
 
+    /// `amount_to_refund` is in the sale units, `token_amount` is what is sent in `token_account_id`.
     pub(crate) fn refund_purchase(&mut self,
                                   recipient_account_id: AccountId,
                                   amount_to_refund: Balance,
                                   token_account_id: AccountId,
+                                  token_amount: Balance,
                                   sale_id: u64) -> Promise {
-        ext_fungible_token::ft_transfer(
+        self.internal_send_deposit_token(
+            sale_id,
             recipient_account_id.clone(),
-            amount_to_refund.into(),
-            Some(format!("Refund {} of {}. Sale #{}", amount_to_refund, token_account_id, sale_id)),
-            token_account_id,
-            ONE_YOCTO,
-            GAS_FOR_FT_TRANSFER,
+            token_account_id.clone(),
+            token_amount,
+            format!("Refund {} of {}. Sale #{}", token_amount, token_account_id, sale_id),
         )
             .then(ext_self::after_refund_purchase(
                 recipient_account_id,
                 amount_to_refund.into(),
+                TokenAmount {
+                    token_id: token_account_id,
+                    amount: token_amount.into(),
+                },
                 sale_id,
                 env::current_account_id(),
                 NO_DEPOSIT,
@@ -1001,17 +1530,27 @@ impl Contract {
         &mut self,
         account_id: AccountId,
         amount_to_refund: U128,
+        token_refund: TokenAmount,
         sale_id: u64,
     ) -> bool {
         let promise_success = is_promise_success();
-        if !promise_success {
+        if promise_success {
+            Event::Refund(vec![AmountData {
+                sale_id,
+                account_id: &account_id,
+                amount: amount_to_refund,
+            }]).emit();
+            self.internal_update_sale_stats(sale_id, |stats| stats.total_refunded += amount_to_refund.0);
+        } else {
             let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
 
             if let Some(v_sale_account) = sale.account_sales.get(&account_id) {
                 let mut account_sale: SaleAccount = v_sale_account.into();
                 account_sale.refunded = U128::from(account_sale.refunded.0 - amount_to_refund.0);
                 sale.account_sales.insert(&account_id, &VSaleAccount::Current(account_sale));
-                self.sales.insert(&sale_id, &VSale::Current(sale));
+                sale.internal_rollback_token_refund(&token_refund.token_id, token_refund.amount.0);
+                sale.internal_rollback_near_payout(&token_refund.token_id, token_refund.amount.0);
+                self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
                 log!("Purchase refund for {} failed. Tokens to recharge: {}", account_id, amount_to_refund.0);
             }
         }
@@ -1024,13 +1563,12 @@ impl Contract {
                                             amount: Balance,
                                             token_account_id: AccountId,
                                             sale_id: u64) -> Promise {
-        ext_fungible_token::ft_transfer(
+        self.internal_send_deposit_token(
+            sale_id,
             recipient_account_id.clone(),
-            amount.into(),
-            Some(format!("Claim affiliate rewards {} of {}. Sale #{}", amount, token_account_id, sale_id)),
-            token_account_id,
-            ONE_YOCTO,
-            GAS_FOR_FT_TRANSFER,
+            token_account_id.clone(),
+            amount,
+            format!("Claim affiliate rewards {} of {}. Sale #{}", amount, token_account_id, sale_id),
         )
             .then(ext_self::after_withdraw_affiliate_reward(
                 recipient_account_id,
@@ -1050,14 +1588,22 @@ impl Contract {
         sale_id: u64,
     ) -> bool {
         let promise_success = is_promise_success();
-        if !promise_success {
+        if promise_success {
+            Event::AffiliateRewardClaim(vec![AmountData {
+                sale_id,
+                account_id: &account_id,
+                amount,
+            }]).emit();
+        } else {
             let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
 
             if let Some(v_sale_account) = sale.account_affiliate_rewards.get(&account_id) {
                 let mut account_affiliate_reward: AffiliateRewardAccount = v_sale_account.into();
                 account_affiliate_reward.claimed = U128::from(account_affiliate_reward.claimed.0 - amount.0);
                 sale.account_affiliate_rewards.insert(&account_id, &VAffiliateRewardAccount::Current(account_affiliate_reward));
-                self.sales.insert(&sale_id, &VSale::Current(sale));
+                let deposit_token_id = sale.deposit_token_id.clone();
+                sale.internal_rollback_near_payout(&deposit_token_id, amount.0);
+                self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
                 log!("Affiliate rewards withdraw for {} failed. Tokens to recharge: {}",account_id, amount.0);
             }
         }
@@ -1066,7 +1612,7 @@ impl Contract {
 }
 
 
-fn is_promise_success() -> bool {
+pub(crate) fn is_promise_success() -> bool {
     assert_eq!(
         env::promise_results_count(),
         1,
@@ -1078,7 +1624,54 @@ fn is_promise_success() -> bool {
     }
 }
 
-fn get_amount_by_subscription(amount_to_claim: Balance, collected_amount: Balance, supply_amount: Balance) -> u128 {
+/// Amount of distribute tokens purchased with the given deposit.
+/// Auctions record tokens per account, here deposit is valued at the settlement price.
+pub(crate) fn get_amount_to_claim(sale: &Sale, deposit_amount: Balance) -> Balance {
+    let distribute_token_decimals = sale.distribute_token_decimals.expect("ERR_NO_TOKEN_DECIMALS");
+    let settlement_price = match sale.sale_type {
+        SaleType::DutchAuction => Some(sale.get_dutch_auction_settlement_price()),
+        SaleType::BatchAuction => Some(sale.clearing_price.expect("ERR_AUCTION_NOT_SETTLED")),
+        SaleType::ByAmount | SaleType::BySubscription | SaleType::Lottery => None,
+    };
+    if let Some(settlement_price) = settlement_price {
+        return (
+            U256::from(u128::pow(10, distribute_token_decimals as u32))
+                * U256::from(deposit_amount)
+                / U256::from(settlement_price)
+        ).as_u128();
+    }
+
+    let total_amount_to_claim: u128 = (
+        U256::from(u128::pow(10, distribute_token_decimals as u32))
+            * U256::from(deposit_amount)
+            / U256::from(sale.price)
+    ).as_u128();
+
+    let total_filled_amount: u128 = (
+        U256::from(u128::pow(10, distribute_token_decimals as u32))
+            * U256::from(sale.get_collected_amount())
+            / U256::from(sale.price)
+    ).as_u128();
+
+    match sale.sale_type {
+        SaleType::ByAmount | SaleType::DutchAuction | SaleType::BatchAuction | SaleType::Lottery => total_amount_to_claim,
+        SaleType::BySubscription => {
+            if sale.max_amount >= sale.get_collected_amount() {
+                total_amount_to_claim
+            } else {
+                get_amount_by_subscription(total_amount_to_claim, total_filled_amount, sale.distribute_supply_amount.expect("ERR_MUST_HAVE_SUPPLY_AMOUNT"))
+            }
+        }
+    }
+}
+
+/// Value of the distribute tokens at the given price, rounded up.
+pub(crate) fn get_purchase_value(sale: &Sale, tokens: Balance, price: Balance) -> Balance {
+    let decimals = U256::from(u128::pow(10, sale.distribute_token_decimals.expect("ERR_NO_TOKEN_DECIMALS") as u32));
+    ((U256::from(tokens) * U256::from(price) + decimals - 1) / decimals).as_u128()
+}
+
+pub(crate) fn get_amount_by_subscription(amount_to_claim: Balance, collected_amount: Balance, supply_amount: Balance) -> u128 {
     (
         U256::from(amount_to_claim)
             * U256::from(supply_amount)
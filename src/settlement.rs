@@ -0,0 +1,179 @@
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+
+use crate::*;
+use crate::sale::*;
+use crate::vesting::get_vested_amount;
+
+/// Expected result of the claims of the account, without changing the state.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SettlementPreview {
+    pub amount: U128,
+    pub amount_to_claim: U128,
+    pub refund: U128,
+    pub claimed: U128,
+    pub refunded: U128,
+    /// Part of amount_to_claim that can be claimed now.
+    pub claimable: U128,
+    /// Part of refund that can be refunded now.
+    pub refundable: U128,
+    pub affiliate_reward: U128,
+    pub affiliate_reward_claimed: bool,
+}
+
+impl Sale {
+    /// Distribute tokens purchased by the account and part of its deposit to refund.
+    /// `internal_calculate_purchase` records this result in the account.
+    pub(crate) fn get_account_settlement(&self, account_sale: &SaleAccount) -> (Balance, Balance) {
+        let deposit_amount = account_sale.amount.0;
+        if self.is_full_refund() {
+            return (0, deposit_amount);
+        }
+        if deposit_amount == 0 {
+            return (0, 0);
+        }
+        if !self.is_price_set() {
+            // Purchase can't be calculated yet, only what was recorded in the account.
+            return (account_sale.amount_to_claim.0, account_sale.refund.0);
+        }
+        match self.sale_type {
+            SaleType::BatchAuction => {
+                if self.clearing_price.is_some() {
+                    self.get_batch_auction_fill(account_sale)
+                } else {
+                    (0, 0)
+                }
+            }
+            SaleType::DutchAuction => {
+                let amount_to_claim = account_sale.amount_to_claim.0;
+                if account_sale.refund.0 > 0 || !self.is_dutch_auction_done(env::block_timestamp()) {
+                    (amount_to_claim, account_sale.refund.0)
+                } else {
+                    let purchase_value = self.get_dutch_auction_purchase_value(amount_to_claim);
+                    (amount_to_claim, deposit_amount.saturating_sub(purchase_value))
+                }
+            }
+            SaleType::ByAmount | SaleType::Lottery | SaleType::BySubscription => {
+                let amount_to_claim = if account_sale.amount_to_claim.0 == 0 {
+                    get_amount_to_claim(self, deposit_amount)
+                } else {
+                    account_sale.amount_to_claim.0
+                };
+                let refund = if self.sale_type == SaleType::BySubscription && account_sale.refund.0 == 0 {
+                    let decimals = self.distribute_token_decimals.expect("ERR_NO_TOKEN_DECIMALS");
+                    let client_purchase_amount: u128 = (
+                        U256::from(amount_to_claim)
+                            * U256::from(self.price)
+                            / U256::from(u128::pow(10, decimals as u32))
+                    ).as_u128();
+                    deposit_amount.saturating_sub(client_purchase_amount)
+                } else {
+                    account_sale.refund.0
+                };
+                (amount_to_claim, refund)
+            }
+        }
+    }
+
+    /// Price and decimals of the distribute token are needed to convert deposits into tokens.
+    /// Sales created before the decimals were stored may not have them.
+    pub(crate) fn is_price_set(&self) -> bool {
+        self.price > 0 && self.distribute_token_decimals.is_some()
+    }
+
+    /// Affiliate rewards are paid only for the oversubscribed subscription sales.
+    pub(crate) fn is_affiliate_reward_available(&self) -> bool {
+        self.sale_type == SaleType::BySubscription
+            && self.max_amount < self.get_collected_amount()
+            && !self.is_full_refund()
+    }
+}
+
+/// Payout of the recorded affiliate reward, reduced by the subscription ratio.
+pub(crate) fn get_affiliate_reward_amount(sale: &Sale, reward_amount: Balance) -> Balance {
+    let distribute_token_decimals = sale.distribute_token_decimals.expect("ERR_NO_TOKEN_DECIMALS");
+    let total_amount_to_claim: u128 = (
+        U256::from(u128::pow(10, distribute_token_decimals as u32))
+            * U256::from(reward_amount)
+            / U256::from(sale.price)
+    ).as_u128();
+
+    let total_filled_amount: u128 = (
+        U256::from(u128::pow(10, distribute_token_decimals as u32))
+            * U256::from(sale.get_collected_amount())
+            / U256::from(sale.price)
+    ).as_u128();
+
+    match sale.sale_type {
+        SaleType::ByAmount | SaleType::DutchAuction | SaleType::BatchAuction | SaleType::Lottery => total_amount_to_claim,
+        SaleType::BySubscription => {
+            get_amount_by_subscription(total_amount_to_claim, total_filled_amount, sale.distribute_supply_amount.expect("ERR_MUST_HAVE_SUPPLY_AMOUNT"))
+        }
+    }
+}
+
+impl Contract {
+    pub(crate) fn internal_preview_settlement(&self, sale: &Sale, account_id: &AccountId) -> SettlementPreview {
+        let account_sale: SaleAccount = sale
+            .account_sales
+            .get(account_id)
+            .map(|account_sale| account_sale.into())
+            .unwrap_or(SaleAccount {
+                amount: U128(0),
+                amount_to_claim: U128(0),
+                claimed: U128(0),
+                refund: U128(0),
+                refunded: U128(0),
+                limit_price: None,
+                token_deposit: None,
+            });
+        let (amount_to_claim, refund) = sale.get_account_settlement(&account_sale);
+
+        let claimable = if sale.claim_available && !sale.is_full_refund() {
+            get_vested_amount(sale, amount_to_claim, env::block_timestamp()).saturating_sub(account_sale.claimed.0)
+        } else {
+            0
+        };
+        let refundable = if sale.refund_available || sale.is_full_refund() {
+            refund.saturating_sub(account_sale.refunded.0)
+        } else {
+            0
+        };
+
+        let (affiliate_reward, affiliate_reward_claimed) = match sale.account_affiliate_rewards.get(account_id) {
+            Some(v_reward) => {
+                let reward: AffiliateRewardAccount = v_reward.into();
+                if reward.claimed.0 > 0 {
+                    (reward.claimed.0, true)
+                } else if sale.is_affiliate_reward_available() && sale.is_price_set() {
+                    (get_affiliate_reward_amount(sale, reward.amount.0), false)
+                } else {
+                    (0, false)
+                }
+            }
+            None => (0, false),
+        };
+
+        SettlementPreview {
+            amount: account_sale.amount,
+            amount_to_claim: U128(amount_to_claim),
+            refund: U128(refund),
+            claimed: account_sale.claimed,
+            refunded: account_sale.refunded,
+            claimable: U128(claimable),
+            refundable: U128(refundable),
+            affiliate_reward: U128(affiliate_reward),
+            affiliate_reward_claimed,
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Tokens and refund the account gets from the sale if it settled now.
+    pub fn preview_settlement(&self, sale_id: u64, account_id: AccountId) -> SettlementPreview {
+        let sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        self.internal_preview_settlement(&sale, &account_id)
+    }
+}
@@ -0,0 +1,59 @@
+use near_sdk::json_types::U128;
+use near_sdk::log;
+
+use crate::*;
+use crate::sale::*;
+
+impl Sale {
+    /// Sale failed if it ended with less than the soft cap collected.
+    pub(crate) fn is_failed(&self) -> bool {
+        match self.soft_cap {
+            Some(soft_cap) => {
+                (self.result.is_some() || env::block_timestamp() > self.end_date)
+                    && self.get_collected_amount() < soft_cap
+            }
+            None => false,
+        }
+    }
+
+    /// All the deposits are returned in full if the sale failed or was cancelled.
+    pub(crate) fn is_full_refund(&self) -> bool {
+        self.cancelled || self.is_failed()
+    }
+}
+
+/// Marks the rest of the account deposit as refunded. Returns amount to send back.
+pub(crate) fn internal_record_full_refund(sale: &mut Sale, account_id: &AccountId) -> Balance {
+    let mut account_sale: SaleAccount = sale.account_sales.get(account_id).expect("ERR_NO_DATA").into();
+    let amount_to_refund = account_sale.amount.0 - account_sale.refunded.0;
+    account_sale.refund = account_sale.amount;
+    account_sale.refunded = account_sale.amount;
+    sale.account_sales.insert(account_id, &VSaleAccount::Current(account_sale));
+    amount_to_refund
+}
+
+impl Contract {
+    /// Returns the whole deposit of the account in the failed or cancelled sale.
+    pub(crate) fn internal_claim_full_refund(&mut self, mut sale: Sale, sale_id: u64, account_id: AccountId) -> Promise {
+        let amount_to_refund = internal_record_full_refund(&mut sale, &account_id);
+        assert_ne!(amount_to_refund, 0, "ERR_ALREADY_REFUNDED");
+        log!("Amount to refund: {}", amount_to_refund);
+        let (token_account_id, token_amount) = sale.internal_record_token_refund(&account_id, amount_to_refund);
+        self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+
+        self.refund_purchase(account_id, amount_to_refund, token_account_id, token_amount, sale_id)
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn is_sale_failed(&self, sale_id: u64) -> bool {
+        let sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        sale.is_failed()
+    }
+
+    pub fn get_soft_cap(&self, sale_id: u64) -> Option<U128> {
+        let sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        sale.soft_cap.map(U128)
+    }
+}
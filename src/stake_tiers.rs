@@ -0,0 +1,62 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+
+use crate::*;
+use crate::sale::*;
+
+/// Accounts with at least `staked_amount` staked can buy for up to `max_buy`.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakeTier {
+    pub staked_amount: U128,
+    /// Guaranteed allocation in decimals of the deposit token.
+    pub max_buy: U128,
+}
+
+pub(crate) fn assert_valid_stake_tiers(stake_tiers: &[StakeTier]) {
+    assert!(!stake_tiers.is_empty(), "ERR_NO_STAKE_TIERS");
+    for (index, tier) in stake_tiers.iter().enumerate() {
+        assert_ne!(tier.max_buy.0, 0, "ERR_WRONG_STAKE_TIER");
+        if index > 0 {
+            assert!(
+                tier.staked_amount.0 > stake_tiers[index - 1].staked_amount.0,
+                "ERR_STAKE_TIERS_NOT_SORTED"
+            );
+        }
+    }
+}
+
+impl Sale {
+    /// Cap of the account without a personal max_buy. Stake tiers replace the global max_buy.
+    pub(crate) fn get_default_max_buy(&self) -> Balance {
+        if self.stake_tiers.is_some() {
+            Balance::MAX
+        } else {
+            self.max_buy
+        }
+    }
+
+    /// Allocation of the highest tier reached with the given stake. None if below the lowest tier.
+    pub(crate) fn get_stake_allocation(&self, staked_amount: Balance) -> Option<Balance> {
+        self.stake_tiers
+            .as_ref()?
+            .iter()
+            .rev()
+            .find(|tier| staked_amount >= tier.staked_amount.0)
+            .map(|tier| tier.max_buy.0)
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Allocation that the given stake guarantees in the sale.
+    pub fn get_stake_allocation(&self, sale_id: u64, staked_amount: U128) -> U128 {
+        let sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        if sale.stake_tiers.is_some() {
+            U128(sale.get_stake_allocation(staked_amount.0).unwrap_or(0))
+        } else {
+            U128(sale.max_buy)
+        }
+    }
+}
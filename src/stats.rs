@@ -0,0 +1,230 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::TreeMap;
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+
+use crate::*;
+use crate::sale::*;
+
+/// Position of the lower median in the sorted deposits.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct MedianCursor {
+    pub value: Balance,
+    /// Position among the deposits of the same amount.
+    pub offset: u64,
+    /// Number of deposits below `value`.
+    pub num_below: u64,
+}
+
+/// Counters of the sale, updated with every deposit, claim and refund.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct SaleStats {
+    /// Number of accounts with each deposit amount.
+    pub deposit_counts: TreeMap<Balance, u64>,
+    /// Accounts with a non zero deposit.
+    pub num_participants: u64,
+    pub median: Option<MedianCursor>,
+    /// Deposits of the accounts that joined with a referrer.
+    pub referred_amount: Balance,
+    pub total_claimed: Balance,
+    pub total_refunded: Balance,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SaleStatsOutput {
+    pub num_participants: u64,
+    pub largest_deposit: U128,
+    pub median_deposit: U128,
+    pub mean_deposit: U128,
+    pub referred_amount: U128,
+    pub total_affiliate_rewards: U128,
+    pub total_claimed: U128,
+    pub total_refunded: U128,
+}
+
+impl SaleStats {
+    pub fn new(sale_id: u64) -> Self {
+        Self {
+            deposit_counts: TreeMap::new(StorageKey::SaleDepositCounts { sale_id }),
+            num_participants: 0,
+            median: None,
+            referred_amount: 0,
+            total_claimed: 0,
+            total_refunded: 0,
+        }
+    }
+
+    fn get_count(&self, amount: Balance) -> u64 {
+        self.deposit_counts.get(&amount).unwrap_or(0)
+    }
+
+    fn step_right(&self, cursor: &mut MedianCursor) {
+        if cursor.offset + 1 < self.get_count(cursor.value) {
+            cursor.offset += 1;
+        } else {
+            cursor.num_below += self.get_count(cursor.value);
+            cursor.value = self.deposit_counts.higher(&cursor.value).unwrap();
+            cursor.offset = 0;
+        }
+    }
+
+    fn step_left(&self, cursor: &mut MedianCursor) {
+        if cursor.offset > 0 {
+            cursor.offset -= 1;
+        } else {
+            cursor.value = self.deposit_counts.lower(&cursor.value).unwrap();
+            cursor.num_below -= self.get_count(cursor.value);
+            cursor.offset = self.get_count(cursor.value) - 1;
+        }
+    }
+
+    /// Moves the cursor to the lower median after a deposit was inserted or removed.
+    fn rebalance_median(&mut self) {
+        if let Some(mut cursor) = self.median.take() {
+            let target = (self.num_participants - 1) / 2;
+            while cursor.num_below + cursor.offset < target {
+                self.step_right(&mut cursor);
+            }
+            while cursor.num_below + cursor.offset > target {
+                self.step_left(&mut cursor);
+            }
+            self.median = Some(cursor);
+        }
+    }
+
+    fn insert_deposit(&mut self, amount: Balance) {
+        self.deposit_counts.insert(&amount, &(self.get_count(amount) + 1));
+        self.num_participants += 1;
+        match self.median.as_mut() {
+            Some(cursor) => {
+                if amount < cursor.value {
+                    cursor.num_below += 1;
+                }
+            }
+            None => {
+                self.median = Some(MedianCursor { value: amount, offset: 0, num_below: 0 });
+            }
+        }
+        self.rebalance_median();
+    }
+
+    fn remove_deposit(&mut self, amount: Balance) {
+        let count = self.deposit_counts.get(&amount).expect("ERR_NO_DEPOSIT") - 1;
+        if count == 0 {
+            self.deposit_counts.remove(&amount);
+        } else {
+            self.deposit_counts.insert(&amount, &count);
+        }
+        self.num_participants -= 1;
+        if self.num_participants == 0 {
+            self.median = None;
+            return;
+        }
+        let mut cursor = self.median.take().unwrap();
+        if amount < cursor.value {
+            cursor.num_below -= 1;
+        } else if amount == cursor.value && cursor.offset >= count {
+            // Cursor pointed at the removed deposit, move it to a neighbour.
+            if count > 0 {
+                cursor.offset = count - 1;
+            } else if let Some(lower) = self.deposit_counts.lower(&amount) {
+                cursor.num_below -= self.get_count(lower);
+                cursor.value = lower;
+                cursor.offset = self.get_count(lower) - 1;
+            } else {
+                cursor.value = self.deposit_counts.higher(&amount).unwrap();
+                cursor.offset = 0;
+            }
+        }
+        self.median = Some(cursor);
+        self.rebalance_median();
+    }
+
+    /// Replaces the deposit of an account. Zero means no deposit.
+    pub(crate) fn update_deposit(&mut self, old_amount: Balance, new_amount: Balance) {
+        if old_amount > 0 {
+            self.remove_deposit(old_amount);
+        }
+        if new_amount > 0 {
+            self.insert_deposit(new_amount);
+        }
+    }
+
+    fn get_median_deposit(&self) -> Balance {
+        match &self.median {
+            Some(cursor) => {
+                if self.num_participants % 2 == 1 {
+                    cursor.value
+                } else {
+                    let next_value = if cursor.offset + 1 < self.get_count(cursor.value) {
+                        cursor.value
+                    } else {
+                        self.deposit_counts.higher(&cursor.value).unwrap()
+                    };
+                    cursor.value / 2 + next_value / 2 + (cursor.value % 2 + next_value % 2) / 2
+                }
+            }
+            None => 0,
+        }
+    }
+}
+
+impl Contract {
+    fn is_referred(&self, account_id: &AccountId) -> bool {
+        self.accounts
+            .get(account_id)
+            .map(|v_account| {
+                let account: Account = v_account.into();
+                account.referrer != self.owner_id
+            })
+            .unwrap_or(false)
+    }
+
+    /// Records the change of the account deposit in the sale counters.
+    pub(crate) fn internal_update_deposit_stats(
+        &self,
+        sale: &mut Sale,
+        account_id: &AccountId,
+        old_amount: Balance,
+        new_amount: Balance,
+    ) {
+        let is_referred = self.is_referred(account_id);
+        if let Some(stats) = sale.stats.as_mut() {
+            stats.update_deposit(old_amount, new_amount);
+            if is_referred {
+                stats.referred_amount = stats.referred_amount + new_amount - old_amount;
+            }
+        }
+    }
+
+    pub(crate) fn internal_update_sale_stats<F: FnOnce(&mut SaleStats)>(&mut self, sale_id: u64, f: F) {
+        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        if let Some(stats) = sale.stats.as_mut() {
+            f(stats);
+            self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_sale_stats(&self, sale_id: u64) -> SaleStatsOutput {
+        let sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        let stats = sale.stats.as_ref().expect("ERR_NO_STATS");
+        SaleStatsOutput {
+            num_participants: stats.num_participants,
+            largest_deposit: U128(stats.deposit_counts.max().unwrap_or(0)),
+            median_deposit: U128(stats.get_median_deposit()),
+            mean_deposit: U128(if stats.num_participants > 0 {
+                sale.collected_amount / stats.num_participants as u128
+            } else {
+                0
+            }),
+            referred_amount: U128(stats.referred_amount),
+            total_affiliate_rewards: U128(sale.total_affiliate_rewards),
+            total_claimed: U128(stats.total_claimed),
+            total_refunded: U128(stats.total_refunded),
+        }
+    }
+}
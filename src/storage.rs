@@ -0,0 +1,143 @@
+use near_contract_standards::storage_management::{
+    StorageBalance, StorageBalanceBounds, StorageManagement,
+};
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::{assert_one_yocto, log, StorageUsage};
+
+use crate::*;
+
+/// Storage to cover joining the contract and participating in a few sales.
+const MIN_STORAGE_BYTES: StorageUsage = 2000;
+
+/// NEAR deposited by the account for storage and bytes it occupies.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct AccountStorage {
+    pub balance: Balance,
+    pub used_bytes: StorageUsage,
+}
+
+impl AccountStorage {
+    fn get_available(&self) -> Balance {
+        self.balance - self.used_bytes as Balance * env::storage_byte_cost()
+    }
+
+    fn to_storage_balance(&self) -> StorageBalance {
+        StorageBalance {
+            total: U128(self.balance),
+            available: U128(self.get_available()),
+        }
+    }
+}
+
+impl Contract {
+    /// Accounts that joined before storage management have no storage deposit, the contract keeps paying for them.
+    /// `join` requires the deposit, so every account that joined after it is charged.
+    fn is_storage_grandfathered(&self, account_id: &AccountId) -> bool {
+        self.storage_accounts.get(account_id).is_none() && self.accounts.get(account_id).is_some()
+    }
+
+    pub(crate) fn assert_storage_registered(&self, account_id: &AccountId) {
+        assert!(
+            self.storage_accounts.get(account_id).is_some() || self.is_storage_grandfathered(account_id),
+            "ERR_STORAGE_NOT_REGISTERED"
+        );
+    }
+
+    /// Charges the account for storage used since `initial_storage_usage`.
+    pub(crate) fn internal_charge_storage(&mut self, account_id: &AccountId, initial_storage_usage: StorageUsage) {
+        if self.is_storage_grandfathered(account_id) {
+            return;
+        }
+        let mut account_storage = self.storage_accounts.get(account_id).expect("ERR_STORAGE_NOT_REGISTERED");
+        let storage_usage = env::storage_usage();
+        if storage_usage > initial_storage_usage {
+            account_storage.used_bytes += storage_usage - initial_storage_usage;
+        } else {
+            account_storage.used_bytes = account_storage.used_bytes.saturating_sub(initial_storage_usage - storage_usage);
+        }
+        assert!(
+            account_storage.balance >= account_storage.used_bytes as Balance * env::storage_byte_cost(),
+            "ERR_NOT_ENOUGH_STORAGE"
+        );
+        self.storage_accounts.insert(account_id, &account_storage);
+    }
+}
+
+#[near_bindgen]
+impl StorageManagement for Contract {
+    #[payable]
+    fn storage_deposit(&mut self, account_id: Option<AccountId>, registration_only: Option<bool>) -> StorageBalance {
+        let amount = env::attached_deposit();
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        let min_balance = self.storage_balance_bounds().min.0;
+        let mut account_storage = self.storage_accounts.get(&account_id).unwrap_or(AccountStorage {
+            balance: 0,
+            used_bytes: 0,
+        });
+        let is_registered = account_storage.balance > 0;
+        let amount = if registration_only.unwrap_or(false) {
+            let to_deposit = if is_registered { 0 } else { min_balance };
+            assert!(amount >= to_deposit, "ERR_STORAGE_DEPOSIT_TOO_SMALL");
+            let refund = amount - to_deposit;
+            if refund > 0 {
+                Promise::new(env::predecessor_account_id()).transfer(refund);
+            }
+            to_deposit
+        } else {
+            amount
+        };
+        assert!(account_storage.balance + amount >= min_balance, "ERR_STORAGE_DEPOSIT_TOO_SMALL");
+        account_storage.balance += amount;
+        log!("Storage deposit {} for {}", amount, account_id);
+        self.storage_accounts.insert(&account_id, &account_storage);
+        account_storage.to_storage_balance()
+    }
+
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let mut account_storage = self.storage_accounts.get(&account_id).expect("ERR_STORAGE_NOT_REGISTERED");
+        let available = account_storage.get_available();
+        let amount = amount.map(|amount| amount.0).unwrap_or(available);
+        assert!(amount <= available, "ERR_NOT_ENOUGH_STORAGE");
+        // Minimal balance stays while the account is registered.
+        assert!(account_storage.balance - amount >= self.storage_balance_bounds().min.0, "ERR_NOT_ENOUGH_STORAGE");
+        account_storage.balance -= amount;
+        self.storage_accounts.insert(&account_id, &account_storage);
+        if amount > 0 {
+            Promise::new(account_id).transfer(amount);
+        }
+        account_storage.to_storage_balance()
+    }
+
+    /// Only accounts that don't occupy any storage can unregister.
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        assert!(!force.unwrap_or(false), "ERR_FORCE_NOT_SUPPORTED");
+        let account_id = env::predecessor_account_id();
+        if let Some(account_storage) = self.storage_accounts.get(&account_id) {
+            assert_eq!(account_storage.used_bytes, 0, "ERR_STORAGE_IN_USE");
+            self.storage_accounts.remove(&account_id);
+            Promise::new(account_id).transfer(account_storage.balance);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        StorageBalanceBounds {
+            min: U128(MIN_STORAGE_BYTES as Balance * env::storage_byte_cost()),
+            max: None,
+        }
+    }
+
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.storage_accounts
+            .get(&account_id)
+            .map(|account_storage| account_storage.to_storage_balance())
+    }
+}
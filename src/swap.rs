@@ -0,0 +1,466 @@
+use near_contract_standards::fungible_token::core_impl::ext_fungible_token;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{ext_contract, log, PromiseError};
+
+use crate::*;
+use crate::sale::*;
+use crate::token_receiver::SaleDeposit;
+
+const GAS_FOR_EXCHANGE_DEPOSIT: Gas = Gas(30_000_000_000_000);
+const GAS_FOR_SWAP: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_EXCHANGE_WITHDRAW: Gas = Gas(20_000_000_000_000);
+const GAS_FOR_ON_SWAP_REFUND: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_AFTER_RETURN_SWAP_TOKEN: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_ON_RECONCILE_SWAP_WITHDRAW: Gas = Gas(
+    GAS_FOR_FT_TRANSFER.0 + GAS_FOR_AFTER_RETURN_SWAP_TOKEN.0 + 5_000_000_000_000
+);
+const GAS_FOR_AFTER_SWAP_SALE_DEPOSIT: Gas = Gas(
+    GAS_FOR_FT_TRANSFER.0 + GAS_FOR_AFTER_RETURN_SWAP_TOKEN.0 + 5_000_000_000_000
+);
+
+/// Ref-style exchange. Tokens are deposited with `ft_transfer_call` and swapped inside of the exchange account.
+#[ext_contract(ext_exchange)]
+pub trait ExtExchange {
+    /// Returns amount of the last token_out.
+    fn swap(&mut self, actions: Vec<SwapAction>, referral_id: Option<AccountId>) -> U128;
+    fn withdraw(&mut self, token_id: AccountId, amount: U128, unregister: Option<bool>);
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapAction {
+    pub pool_id: u64,
+    pub token_in: AccountId,
+    pub amount_in: Option<U128>,
+    pub token_out: AccountId,
+    pub min_amount_out: U128,
+}
+
+/// Input token that can be swapped into deposit_token_id through the pool.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapPool {
+    pub token_id: AccountId,
+    pub pool_id: u64,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapConfig {
+    pub exchange_id: AccountId,
+    /// Allowlisted input tokens.
+    pub pools: Vec<SwapPool>,
+}
+
+/// Swap instruction of the deposit.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapDeposit {
+    /// Minimum amount of deposit_token_id to receive, otherwise the input is returned.
+    pub min_amount_out: U128,
+}
+
+/// Swap deposit of the account passed between the callbacks until the output is deposited into the sale.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingSwap {
+    pub exchange_id: AccountId,
+    pub sender_id: AccountId,
+    pub sale_deposit: SaleDeposit,
+}
+
+/// Tokens of the swap deposits of the account that could neither be credited to the sale nor sent back.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct UnreturnedSwap {
+    pub exchange_id: AccountId,
+    pub token_id: AccountId,
+    /// Left on the account of the contract in the exchange.
+    pub exchange_amount: U128,
+    /// Withdrawn from the exchange and held by the contract.
+    pub held_amount: U128,
+}
+
+/// Gas of the steps of the swap deposit grows with the gas of the deposit into the sale at the end of the chain.
+impl Sale {
+    fn get_on_swap_withdraw_gas(&self) -> Gas {
+        Gas(self.get_deposit_gas().0 + GAS_FOR_AFTER_SWAP_SALE_DEPOSIT.0 + 5_000_000_000_000)
+    }
+
+    fn get_on_swap_gas(&self) -> Gas {
+        Gas(GAS_FOR_EXCHANGE_WITHDRAW.0 + self.get_on_swap_withdraw_gas().0 + 5_000_000_000_000)
+    }
+
+    fn get_on_swap_deposit_gas(&self) -> Gas {
+        Gas(GAS_FOR_SWAP.0 + self.get_on_swap_gas().0 + 5_000_000_000_000)
+    }
+
+    /// Gas `ft_on_transfer` of the swap deposit must be called with.
+    pub(crate) fn get_swap_deposit_gas(&self) -> Gas {
+        Gas(GAS_FOR_EXCHANGE_DEPOSIT.0 + self.get_on_swap_deposit_gas().0 + 10_000_000_000_000)
+    }
+}
+
+impl Contract {
+    /// Deposits the input token into the exchange and swaps it into deposit_token_id.
+    /// Only the swapped output is credited to the sale. Returns the unused input amount.
+    ///
+    /// Input is returned through `ft_resolve_transfer` if the swap fails.
+    /// Output that the sale doesn't accept is sent back in deposit_token_id.
+    pub(crate) fn internal_swap_deposit(
+        &mut self,
+        token_id: AccountId,
+        sender_id: AccountId,
+        amount: U128,
+        swap: SwapDeposit,
+        sale_deposit: SaleDeposit,
+    ) -> PromiseOrValue<U128> {
+        let sale: Sale = self.sales.get(&sale_deposit.sale_id).expect("ERR_NO_SALE").into();
+        assert!(!sale.cancelled, "ERR_SALE_CANCELLED");
+        sale.assert_not_finalized();
+        let timestamp = env::block_timestamp();
+        assert!(timestamp >= sale.start_date, "ERR_SALE_NOT_STARTED");
+        assert!(timestamp <= sale.end_date, "ERR_SALE_DONE");
+        // Without enough gas the chain would stop with the input on the exchange
+        // or the output would be returned after paying for the swap.
+        assert!(env::prepaid_gas() >= sale.get_swap_deposit_gas(), "ERR_NOT_ENOUGH_GAS");
+        let on_swap_deposit_gas = sale.get_on_swap_deposit_gas();
+        let swap_config = sale.swap.expect("ERR_SWAP_NOT_ALLOWED");
+        let pool = swap_config
+            .pools
+            .iter()
+            .find(|pool| pool.token_id == token_id)
+            .expect("ERR_TOKEN_NOT_ALLOWED");
+        let action = SwapAction {
+            pool_id: pool.pool_id,
+            token_in: token_id.clone(),
+            amount_in: Some(amount),
+            token_out: sale.deposit_token_id,
+            min_amount_out: swap.min_amount_out,
+        };
+        log!("Swap {} of {} for sale #{}", amount.0, token_id, sale_deposit.sale_id);
+
+        PromiseOrValue::Promise(
+            ext_fungible_token::ft_transfer_call(
+                swap_config.exchange_id.clone(),
+                amount,
+                None,
+                "".to_string(),
+                token_id,
+                ONE_YOCTO,
+                GAS_FOR_EXCHANGE_DEPOSIT,
+            )
+            .then(ext_self::on_swap_deposit(
+                action,
+                PendingSwap {
+                    exchange_id: swap_config.exchange_id,
+                    sender_id,
+                    sale_deposit,
+                },
+                env::current_account_id(),
+                NO_DEPOSIT,
+                on_swap_deposit_gas,
+            )),
+        )
+    }
+
+    /// Sends the token back to the account, recording it as unreturned if the transfer fails.
+    pub(crate) fn internal_return_swap_token(
+        &mut self,
+        account_id: AccountId,
+        exchange_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+    ) -> Promise {
+        ext_fungible_token::ft_transfer(
+            account_id.clone(),
+            amount,
+            Some("Unused swap deposit".to_string()),
+            token_id.clone(),
+            ONE_YOCTO,
+            GAS_FOR_FT_TRANSFER,
+        )
+        .then(ext_self::after_return_swap_token(
+            account_id,
+            exchange_id,
+            token_id,
+            amount,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_AFTER_RETURN_SWAP_TOKEN,
+        ))
+    }
+
+    pub(crate) fn internal_record_unreturned_swap(
+        &mut self,
+        account_id: &AccountId,
+        exchange_id: &AccountId,
+        token_id: &AccountId,
+        exchange_amount: Balance,
+        held_amount: Balance,
+    ) {
+        let mut unreturned_swaps = self.unreturned_swaps.get(account_id).unwrap_or_default();
+        if let Some(unreturned_swap) = unreturned_swaps
+            .iter_mut()
+            .find(|unreturned_swap| &unreturned_swap.exchange_id == exchange_id && &unreturned_swap.token_id == token_id)
+        {
+            unreturned_swap.exchange_amount = U128(unreturned_swap.exchange_amount.0 + exchange_amount);
+            unreturned_swap.held_amount = U128(unreturned_swap.held_amount.0 + held_amount);
+        } else {
+            unreturned_swaps.push(UnreturnedSwap {
+                exchange_id: exchange_id.clone(),
+                token_id: token_id.clone(),
+                exchange_amount: U128(exchange_amount),
+                held_amount: U128(held_amount),
+            });
+        }
+        self.unreturned_swaps.insert(account_id, &unreturned_swaps);
+        log!("Unreturned swap of {}: {} of {} on {}, held: {}", account_id, exchange_amount, token_id, exchange_id, held_amount);
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Swaps the input after it is deposited into the exchange.
+    #[private]
+    pub fn on_swap_deposit(
+        &mut self,
+        #[callback_result] used_amount: Result<U128, PromiseError>,
+        action: SwapAction,
+        swap: PendingSwap,
+    ) -> PromiseOrValue<U128> {
+        let amount = action.amount_in.expect("ERR_NO_AMOUNT");
+        let used_amount = used_amount.map(|used_amount| used_amount.0).unwrap_or(0);
+        if used_amount != amount.0 {
+            log!("Swap deposit of {} failed. Input to return: {}", swap.sender_id, amount.0 - used_amount);
+            return PromiseOrValue::Value(U128(amount.0 - used_amount));
+        }
+        let sale: Sale = self.sales.get(&swap.sale_deposit.sale_id).expect("ERR_NO_SALE").into();
+        ext_exchange::swap(vec![action.clone()], None, swap.exchange_id.clone(), NO_DEPOSIT, GAS_FOR_SWAP)
+            .then(ext_self::on_swap(
+                action,
+                swap,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                sale.get_on_swap_gas(),
+            ))
+            .into()
+    }
+
+    /// Withdraws the output of the swap, or the input if the swap failed.
+    #[private]
+    pub fn on_swap(
+        &mut self,
+        #[callback_result] amount_out: Result<U128, PromiseError>,
+        action: SwapAction,
+        swap: PendingSwap,
+    ) -> Promise {
+        let token_in = action.token_in;
+        let amount_in = action.amount_in.expect("ERR_NO_AMOUNT");
+        let token_out = action.token_out;
+        match amount_out {
+            Ok(amount_out) => {
+                let sale: Sale = self.sales.get(&swap.sale_deposit.sale_id).expect("ERR_NO_SALE").into();
+                log!("Swapped {} of {} into {} of {}", amount_in.0, token_in, amount_out.0, token_out);
+                ext_exchange::withdraw(
+                    token_out.clone(),
+                    amount_out,
+                    None,
+                    swap.exchange_id.clone(),
+                    ONE_YOCTO,
+                    GAS_FOR_EXCHANGE_WITHDRAW,
+                )
+                .then(ext_self::on_swap_withdraw(
+                    token_out,
+                    amount_out,
+                    swap,
+                    env::current_account_id(),
+                    NO_DEPOSIT,
+                    sale.get_on_swap_withdraw_gas(),
+                ))
+            }
+            Err(_) => {
+                log!("Swap of {} for {} failed. Input to return: {}", token_in, swap.sender_id, amount_in.0);
+                ext_exchange::withdraw(
+                    token_in.clone(),
+                    amount_in,
+                    None,
+                    swap.exchange_id.clone(),
+                    ONE_YOCTO,
+                    GAS_FOR_EXCHANGE_WITHDRAW,
+                )
+                .then(ext_self::on_swap_refund(
+                    swap.exchange_id,
+                    token_in,
+                    amount_in,
+                    swap.sender_id,
+                    env::current_account_id(),
+                    NO_DEPOSIT,
+                    GAS_FOR_ON_SWAP_REFUND,
+                ))
+            }
+        }
+    }
+
+    /// Input withdrawn from the exchange is returned to the sender by `ft_resolve_transfer`.
+    #[private]
+    pub fn on_swap_refund(
+        &mut self,
+        exchange_id: AccountId,
+        token_in: AccountId,
+        amount_in: U128,
+        sender_id: AccountId,
+    ) -> U128 {
+        if is_promise_success() {
+            amount_in
+        } else {
+            self.internal_record_unreturned_swap(&sender_id, &exchange_id, &token_in, amount_in.0, 0);
+            U128(0)
+        }
+    }
+
+    /// Credits the withdrawn output to the sale.
+    #[private]
+    pub fn on_swap_withdraw(&mut self, token_out: AccountId, amount_out: U128, swap: PendingSwap) -> PromiseOrValue<U128> {
+        let PendingSwap { exchange_id, sender_id, sale_deposit } = swap;
+        if !is_promise_success() {
+            self.internal_record_unreturned_swap(&sender_id, &exchange_id, &token_out, amount_out.0, 0);
+            return PromiseOrValue::Value(U128(0));
+        }
+        let sale: Sale = self.sales.get(&sale_deposit.sale_id).expect("ERR_NO_SALE").into();
+        ext_self::swap_sale_deposit(
+            token_out.clone(),
+            sender_id.clone(),
+            amount_out,
+            sale_deposit,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            sale.get_deposit_gas(),
+        )
+        .then(ext_self::after_swap_sale_deposit(
+            exchange_id,
+            token_out,
+            sender_id,
+            amount_out,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_AFTER_SWAP_SALE_DEPOSIT,
+        ))
+        .into()
+    }
+
+    /// Deposits the swapped output like a transfer of deposit_token_id from the sender.
+    /// Separate call so that a failed deposit can still return the output.
+    #[private]
+    pub fn swap_sale_deposit(
+        &mut self,
+        token_id: AccountId,
+        sender_id: AccountId,
+        amount: U128,
+        sale_deposit: SaleDeposit,
+    ) -> PromiseOrValue<U128> {
+        self.internal_ft_on_transfer(token_id, sender_id, amount, sale_deposit)
+    }
+
+    /// Sends back the output that was not credited. Input of the swap is always used in full.
+    #[private]
+    pub fn after_swap_sale_deposit(
+        &mut self,
+        #[callback_result] unused_amount: Result<U128, PromiseError>,
+        exchange_id: AccountId,
+        token_id: AccountId,
+        sender_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        let unused_amount = unused_amount.map(|unused_amount| unused_amount.0).unwrap_or(amount.0);
+        if unused_amount > 0 {
+            log!("Swap output to return to {}: {}", sender_id, unused_amount);
+            self.internal_return_swap_token(sender_id, exchange_id, token_id, U128(unused_amount));
+        }
+        U128(0)
+    }
+
+    #[private]
+    pub fn after_return_swap_token(
+        &mut self,
+        account_id: AccountId,
+        exchange_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+    ) -> bool {
+        let promise_success = is_promise_success();
+        if !promise_success {
+            self.internal_record_unreturned_swap(&account_id, &exchange_id, &token_id, 0, amount.0);
+        }
+        promise_success
+    }
+
+    /// Withdraws the tokens left on the exchange and sends them with the held tokens back to the account.
+    pub fn reconcile_unreturned_swaps(&mut self, account_id: AccountId) {
+        assert_eq!(
+            self.owner_id,
+            env::predecessor_account_id(),
+            "ERR_MUST_BE_OWNER"
+        );
+        let unreturned_swaps = self.unreturned_swaps.remove(&account_id).expect("ERR_NO_DATA");
+        for unreturned_swap in unreturned_swaps {
+            if unreturned_swap.exchange_amount.0 > 0 {
+                ext_exchange::withdraw(
+                    unreturned_swap.token_id.clone(),
+                    unreturned_swap.exchange_amount,
+                    None,
+                    unreturned_swap.exchange_id.clone(),
+                    ONE_YOCTO,
+                    GAS_FOR_EXCHANGE_WITHDRAW,
+                )
+                .then(ext_self::on_reconcile_swap_withdraw(
+                    account_id.clone(),
+                    unreturned_swap.exchange_id.clone(),
+                    unreturned_swap.token_id.clone(),
+                    unreturned_swap.exchange_amount,
+                    env::current_account_id(),
+                    NO_DEPOSIT,
+                    GAS_FOR_ON_RECONCILE_SWAP_WITHDRAW,
+                ));
+            }
+            if unreturned_swap.held_amount.0 > 0 {
+                self.internal_return_swap_token(
+                    account_id.clone(),
+                    unreturned_swap.exchange_id,
+                    unreturned_swap.token_id,
+                    unreturned_swap.held_amount,
+                );
+            }
+        }
+    }
+
+    #[private]
+    pub fn on_reconcile_swap_withdraw(
+        &mut self,
+        account_id: AccountId,
+        exchange_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+    ) -> PromiseOrValue<bool> {
+        if is_promise_success() {
+            self.internal_return_swap_token(account_id, exchange_id, token_id, amount).into()
+        } else {
+            self.internal_record_unreturned_swap(&account_id, &exchange_id, &token_id, amount.0, 0);
+            PromiseOrValue::Value(false)
+        }
+    }
+
+    pub fn get_unreturned_swaps(&self, account_id: AccountId) -> Vec<UnreturnedSwap> {
+        self.unreturned_swaps.get(&account_id).unwrap_or_default()
+    }
+
+    pub fn get_unreturned_swap_accounts(&self, from_index: u64, limit: u64) -> Vec<(AccountId, Vec<UnreturnedSwap>)> {
+        let keys = self.unreturned_swaps.keys_as_vector();
+        let values = self.unreturned_swaps.values_as_vector();
+        (from_index..std::cmp::min(from_index + limit, keys.len()))
+            .map(|index| (keys.get(index).unwrap(), values.get(index).unwrap()))
+            .collect()
+    }
+}
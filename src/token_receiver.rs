@@ -1,14 +1,20 @@
 use near_sdk::json_types::U128;
 use near_sdk::serde::{Deserialize, Serialize};
-use near_sdk::{env, ext_contract, serde_json};
+use near_sdk::{env, ext_contract, log, serde_json};
 
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
 
-use crate::sale::Sale;
+use crate::native_near::is_native_near_token;
+use crate::price_oracle::{GAS_GET_USD_PRICE, GAS_ON_GET_USD_PRICE};
+use crate::sale::{Sale, VSale};
+use crate::swap::SwapDeposit;
+use crate::whitelist::*;
 use crate::*;
 
 const GAS_GET_ACCOUNT_STAKED_BALANCE: Gas = Gas(25_000_000_000_000);
 const GAS_ON_GET_ACCOUNT_STAKED_BALANCE: Gas = Gas(25_000_000_000_000);
+/// Execution of `internal_ft_on_transfer` itself, without the calls it schedules.
+const GAS_FOR_SALE_DEPOSIT: Gas = Gas(20_000_000_000_000);
 const NO_DEPOSIT: Balance = 0;
 
 #[ext_contract(ext_staking_pool)]
@@ -23,27 +29,76 @@ pub struct SaleDeposit {
     pub sale_id: u64,
     /// Optional argument to point to the contract where this user has staked if sale requires this.
     pub staking_contract: Option<AccountId>,
+    /// Merkle proof of the account if sale has whitelist.
+    pub whitelist_proof: Option<WhitelistProof>,
+    /// Highest price the account is willing to pay. Only for sale_type: BatchAuction
+    pub limit_price: Option<U128>,
+    /// Swap the transferred token into deposit_token_id of the sale before the deposit.
+    pub swap: Option<SwapDeposit>,
+}
+
+/// Deposit waiting for the stake or the USD price of the token, passed between the callbacks.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingDeposit {
+    pub sale_id: u64,
+    pub token_id: AccountId,
+    pub sender_id: AccountId,
+    /// Amount of token_id transferred by the sender.
+    pub amount: U128,
+    pub max_buy: U128,
+    pub limit_price: Option<U128>,
+}
+
+/// Message of `ft_on_transfer`: either a deposit into the sale
+/// or `{"fund_sale": <sale_id>}` to escrow distribute tokens for the sale.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde", untagged)]
+pub enum TokenReceiverMessage {
+    FundSale { fund_sale: u64 },
+    SaleDeposit(SaleDeposit),
 }
 
 impl Contract {
+    /// Records distribute tokens sent by the project to pay out the claims.
+    pub(crate) fn internal_fund_sale(&mut self, token_id: AccountId, sender_id: AccountId, amount: U128, sale_id: u64) -> PromiseOrValue<U128> {
+        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        assert_eq!(
+            sale.distribute_token_id.as_ref().expect("ERR_NO_TOKEN_ID"),
+            &token_id,
+            "ERR_WRONG_TOKEN"
+        );
+        sale.distribute_escrow_amount += amount.0;
+        log!("{} funded sale #{} with {}. Escrow: {}", sender_id, sale_id, amount.0, sale.distribute_escrow_amount);
+        self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+        PromiseOrValue::Value(U128(0))
+    }
+
     pub fn internal_ft_on_transfer(
         &mut self,
         token_id: AccountId,
         sender_id: AccountId,
         amount: U128,
-        sale_deposit: SaleDeposit,
+        mut sale_deposit: SaleDeposit,
     ) -> PromiseOrValue<U128> {
         // Check that account is registered.
         let _ = self
             .accounts
             .get(&sender_id)
             .expect("ERR_NOT_REGISTERED_ACCOUNT");
+        self.assert_storage_registered(&sender_id);
+        // Swapped output comes back through `swap_sale_deposit` without the swap instruction.
+        if let Some(swap) = sale_deposit.swap.take() {
+            return self.internal_swap_deposit(token_id, sender_id, amount, swap, sale_deposit);
+        }
         let sale: Sale = self
             .sales
             .get(&sale_deposit.sale_id)
             .expect("ERR_NO_SALE")
             .into();
-        assert_eq!(sale.deposit_token_id, token_id, "ERR_WRONG_TOKEN");
+        sale.assert_deposit_token(&token_id);
+        assert!(!sale.cancelled, "ERR_SALE_CANCELLED");
+        sale.assert_not_finalized();
         if sale.hard_max_amount_limit {
             assert!(
                 sale.collected_amount < sale.max_amount,
@@ -56,6 +111,15 @@ impl Contract {
             timestamp >= sale.start_date && timestamp <= sale.end_date,
             "ERR_SALE_DONE"
         );
+        let max_buy = internal_get_max_buy(&sale, &sender_id, sale_deposit.whitelist_proof);
+        let deposit = PendingDeposit {
+            sale_id: sale_deposit.sale_id,
+            token_id,
+            sender_id,
+            amount,
+            max_buy: U128(max_buy),
+            limit_price: sale_deposit.limit_price,
+        };
 
         // Send call to check how much is staked if staking is required.
         if sale.staking_contracts.len() > 0 {
@@ -68,37 +132,72 @@ impl Contract {
             );
             PromiseOrValue::Promise(
                 ext_staking_pool::get_account_staked_balance(
-                    sender_id.clone(),
+                    deposit.sender_id.clone(),
                     staking_contract,
                     NO_DEPOSIT,
                     GAS_GET_ACCOUNT_STAKED_BALANCE,
                 )
                 .then(ext_self::on_get_account_staked_balance(
-                    sale_deposit.sale_id,
-                    token_id,
-                    sender_id,
-                    amount,
+                    deposit,
                     env::current_account_id(),
                     NO_DEPOSIT,
-                    GAS_ON_GET_ACCOUNT_STAKED_BALANCE,
+                    if sale.usd_oracle.is_some() {
+                        GAS_ON_GET_ACCOUNT_STAKED_BALANCE + GAS_GET_USD_PRICE + GAS_ON_GET_USD_PRICE
+                    } else {
+                        GAS_ON_GET_ACCOUNT_STAKED_BALANCE
+                    },
                 )),
             )
         } else {
-            PromiseOrValue::Value(U128(self.internal_sale_deposit(
-                sale_deposit.sale_id,
-                &token_id,
-                &sender_id,
-                0,
-                amount.0,
-            )))
+            self.internal_deposit_with_price(&sale, deposit, 0)
+        }
+    }
+}
+
+impl Sale {
+    /// Gas that a deposit into the sale needs, including the stake and the USD price checks it schedules.
+    /// Deposits forwarded by this contract, like the wrapped NEAR or the swap output, are called with it.
+    pub(crate) fn get_deposit_gas(&self) -> Gas {
+        let mut gas = GAS_FOR_SALE_DEPOSIT.0;
+        if !self.staking_contracts.is_empty() {
+            gas += GAS_GET_ACCOUNT_STAKED_BALANCE.0 + GAS_ON_GET_ACCOUNT_STAKED_BALANCE.0;
+        }
+        if self.usd_oracle.is_some() {
+            gas += GAS_GET_USD_PRICE.0 + GAS_ON_GET_USD_PRICE.0;
         }
+        Gas(gas)
+    }
+}
+
+/// Returns max_buy for the given account. Panics if sale has whitelist and account is not in it,
+/// or if sale is a lottery and account is not a winner.
+fn internal_get_max_buy(sale: &Sale, account_id: &AccountId, whitelist_proof: Option<WhitelistProof>) -> Balance {
+    let max_buy = internal_get_whitelist_max_buy(sale, account_id, whitelist_proof);
+    if sale.lottery.is_some() {
+        std::cmp::min(max_buy, sale.assert_lottery_winner(account_id))
+    } else {
+        max_buy
+    }
+}
+
+fn internal_get_whitelist_max_buy(sale: &Sale, account_id: &AccountId, whitelist_proof: Option<WhitelistProof>) -> Balance {
+    if let Some(whitelist_hash) = sale.whitelist_hash {
+        let whitelist_proof = whitelist_proof.expect("ERR_MUST_HAVE_WHITELIST_PROOF");
+        let max_buy = whitelist_proof.max_buy.map(|max_buy| max_buy.0);
+        assert!(
+            verify_whitelist_proof(&whitelist_hash, whitelist_leaf(account_id, max_buy), &whitelist_proof.proof),
+            "ERR_NOT_WHITELISTED"
+        );
+        max_buy.unwrap_or_else(|| sale.get_default_max_buy())
+    } else {
+        sale.get_default_max_buy()
     }
 }
 
 #[near_bindgen]
 impl FungibleTokenReceiver for Contract {
     /// Callback on receiving tokens by this contract.
-    /// Record the AccountSale for given Sale.
+    /// Record the AccountSale for given Sale or the escrow of distribute tokens.
     #[allow(unused_variables)]
     fn ft_on_transfer(
         &mut self,
@@ -106,12 +205,21 @@ impl FungibleTokenReceiver for Contract {
         amount: U128,
         msg: String,
     ) -> PromiseOrValue<U128> {
-        let sale_deposit = serde_json::from_str::<SaleDeposit>(&msg).expect("ERR_MSG_WRONG_FORMAT");
-        self.internal_ft_on_transfer(
-            env::predecessor_account_id(),
-            sender_id,
-            amount,
-            sale_deposit,
-        )
+        // Native NEAR only comes through `deposit_near`.
+        assert!(!is_native_near_token(&env::predecessor_account_id()), "ERR_WRONG_TOKEN");
+        match serde_json::from_str::<TokenReceiverMessage>(&msg).expect("ERR_MSG_WRONG_FORMAT") {
+            TokenReceiverMessage::FundSale { fund_sale } => self.internal_fund_sale(
+                env::predecessor_account_id(),
+                sender_id,
+                amount,
+                fund_sale,
+            ),
+            TokenReceiverMessage::SaleDeposit(sale_deposit) => self.internal_ft_on_transfer(
+                env::predecessor_account_id(),
+                sender_id,
+                amount,
+                sale_deposit,
+            ),
+        }
     }
 }
@@ -0,0 +1,109 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::Timestamp;
+
+use crate::*;
+use crate::events::emit_sale_update;
+use crate::sale::*;
+
+/// Denominator for `tge_percent`: 1 => 0.01%
+pub(crate) const VESTING_DENOMINATOR: u128 = 10000;
+
+/// Release schedule of the purchased tokens.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VestingSchedule {
+    /// Token generation event. Nothing is released before this date.
+    pub tge_date: U64,
+    /// Part of the purchase released at TGE, 1 => 0.01% (value / 10000)
+    pub tge_percent: u64,
+    /// Period after TGE when nothing else is released.
+    pub cliff_duration: U64,
+    /// Period after the cliff during which the rest is released.
+    pub vesting_duration: U64,
+    /// Release the rest in steps of this length. Zero for linear release.
+    pub vesting_interval: U64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VestingOutput {
+    pub amount_to_claim: U128,
+    pub vested: U128,
+    pub claimed: U128,
+    pub claimable: U128,
+}
+
+impl VestingSchedule {
+    pub(crate) fn assert_valid(&self) {
+        assert!(self.tge_percent as u128 <= VESTING_DENOMINATOR, "ERR_WRONG_TGE_PERCENT");
+        assert!(self.vesting_interval.0 <= self.vesting_duration.0, "ERR_WRONG_VESTING_INTERVAL");
+    }
+
+    /// Amount of `total` released by the given timestamp.
+    pub(crate) fn get_vested_amount(&self, total: Balance, timestamp: Timestamp) -> Balance {
+        if timestamp < self.tge_date.0 {
+            return 0;
+        }
+        let tge_amount = (U256::from(total) * U256::from(self.tge_percent) / U256::from(VESTING_DENOMINATOR)).as_u128();
+        let vesting_start = self.tge_date.0 + self.cliff_duration.0;
+        if timestamp < vesting_start {
+            return tge_amount;
+        }
+        if self.vesting_duration.0 == 0 {
+            return total;
+        }
+        let mut elapsed = std::cmp::min(timestamp - vesting_start, self.vesting_duration.0);
+        if self.vesting_interval.0 > 0 && elapsed < self.vesting_duration.0 {
+            elapsed -= elapsed % self.vesting_interval.0;
+        }
+        tge_amount + (
+            U256::from(total - tge_amount)
+                * U256::from(elapsed)
+                / U256::from(self.vesting_duration.0)
+        ).as_u128()
+    }
+}
+
+/// Amount of purchased tokens released by the given timestamp. Sales without vesting release everything at once.
+pub(crate) fn get_vested_amount(sale: &Sale, amount_to_claim: Balance, timestamp: Timestamp) -> Balance {
+    if let Some(vesting) = &sale.vesting {
+        vesting.get_vested_amount(amount_to_claim, timestamp)
+    } else {
+        amount_to_claim
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Vested and claimable amounts of the purchase at the given timestamp (now by default).
+    pub fn get_vesting(&self, sale_id: u64, account_id: AccountId, timestamp: Option<U64>) -> VestingOutput {
+        let sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        let timestamp = timestamp.map(|t| t.0).unwrap_or_else(env::block_timestamp);
+        let account_sale: SaleAccount = sale.account_sales.get(&account_id).expect("ERR_NO_DATA").into();
+
+        // Empty until the purchase is known, like the unsettled batch auction.
+        let (amount_to_claim, _) = sale.get_account_settlement(&account_sale);
+        let vested = get_vested_amount(&sale, amount_to_claim, timestamp);
+
+        VestingOutput {
+            amount_to_claim: U128(amount_to_claim),
+            vested: U128(vested),
+            claimed: account_sale.claimed,
+            claimable: U128(vested.saturating_sub(account_sale.claimed.0)),
+        }
+    }
+
+    #[private]
+    pub fn update_sale_vesting(&mut self, sale_id: u64, vesting: Option<VestingSchedule>) {
+        let mut sale: Sale = self.sales.get(&sale_id).expect("ERR_NO_SALE").into();
+        assert!(!sale.claim_available, "ERR_CLAIM_ALREADY_AVAILABLE");
+        if let Some(vesting) = &vesting {
+            vesting.assert_valid();
+        }
+        sale.vesting = vesting;
+        self.sales.insert(&sale_id, &VSale::Current(Box::new(sale)));
+        emit_sale_update(sale_id, "vesting");
+    }
+}
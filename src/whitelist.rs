@@ -0,0 +1,37 @@
+use std::convert::TryInto;
+
+use near_sdk::{AccountId, Balance, CryptoHash, env};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+
+/// Proof that account is included into the merkle tree of the sale whitelist.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WhitelistProof {
+    /// Personal max_buy of the account if it was set in the leaf.
+    pub max_buy: Option<U128>,
+    /// Sibling hashes from the leaf up to the root.
+    pub proof: Vec<CryptoHash>,
+}
+
+/// Leaf of the whitelist tree: sha256("<account_id>") or sha256("<account_id>:<max_buy>").
+pub(crate) fn whitelist_leaf(account_id: &AccountId, max_buy: Option<Balance>) -> CryptoHash {
+    let leaf = match max_buy {
+        Some(max_buy) => format!("{}:{}", account_id, max_buy),
+        None => account_id.to_string(),
+    };
+    hash(leaf.as_bytes())
+}
+
+/// Pairs are hashed in sorted order, so proof doesn't need to know the side of each sibling.
+pub(crate) fn verify_whitelist_proof(root: &CryptoHash, leaf: CryptoHash, proof: &[CryptoHash]) -> bool {
+    let computed = proof.iter().fold(leaf, |node, sibling| {
+        let (left, right) = if node <= *sibling { (node, *sibling) } else { (*sibling, node) };
+        hash(&[left, right].concat())
+    });
+    &computed == root
+}
+
+fn hash(value: &[u8]) -> CryptoHash {
+    env::sha256(value).try_into().unwrap()
+}